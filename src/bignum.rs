@@ -0,0 +1,267 @@
+//! Arithmetic on arbitrary precision magnitudes, stored as little endian
+//! 32 bit digits without leading zero digits. Zero has no digits at all.
+//!
+//! Signs are handled by `HBigInt`, everything here is unsigned.
+
+use std::cmp::Ordering;
+
+pub type Digits = Vec<u32>;
+
+const DIGIT_BITS: u32 = 32;
+
+/// Strips leading zero digits.
+pub fn normalize(mut digits: Digits) -> Digits {
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    digits
+}
+
+pub fn from_u128(mut value: u128) -> Digits {
+    let mut digits = Vec::new();
+    while value != 0 {
+        digits.push(value as u32);
+        value >>= DIGIT_BITS;
+    }
+    digits
+}
+
+/// Value of `a` if it fits 128 bits.
+pub fn to_u128(a: &[u32]) -> Option<u128> {
+    if a.len() > 4 {
+        return None;
+    }
+    Some(
+        a.iter()
+            .rev()
+            .fold(0u128, |acc, &digit| (acc << DIGIT_BITS) | digit as u128),
+    )
+}
+
+/// Nearest double, may round more than once for very long magnitudes.
+pub fn to_f64(a: &[u32]) -> f64 {
+    a.iter()
+        .rev()
+        .fold(0f64, |acc, &digit| acc * 4294967296.0 + digit as f64)
+}
+
+pub fn compare(a: &[u32], b: &[u32]) -> Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+pub fn add(a: &[u32], b: &[u32]) -> Digits {
+    let (long, short) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut result = Vec::with_capacity(long.len() + 1);
+    let mut carry = 0u64;
+    for (i, &digit) in long.iter().enumerate() {
+        let sum = digit as u64 + *short.get(i).unwrap_or(&0) as u64 + carry;
+        result.push(sum as u32);
+        carry = sum >> DIGIT_BITS;
+    }
+    if carry != 0 {
+        result.push(carry as u32);
+    }
+    result
+}
+
+/// `a - b`, `a` has to be at least `b`.
+pub fn sub(a: &[u32], b: &[u32]) -> Digits {
+    debug_assert!(compare(a, b) != Ordering::Less);
+    let mut result = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for (i, &digit) in a.iter().enumerate() {
+        let mut diff = digit as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << DIGIT_BITS;
+            borrow = 1;
+        }
+        result.push(diff as u32);
+    }
+    normalize(result)
+}
+
+pub fn mul(a: &[u32], b: &[u32]) -> Digits {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut result = vec![0u32; a.len() + b.len()];
+    for (i, &x) in a.iter().enumerate() {
+        let mut carry = 0u64;
+        for (j, &y) in b.iter().enumerate() {
+            let product = x as u64 * y as u64 + result[i + j] as u64 + carry;
+            result[i + j] = product as u32;
+            carry = product >> DIGIT_BITS;
+        }
+        result[i + b.len()] = carry as u32;
+    }
+    normalize(result)
+}
+
+/// Quotient and remainder of dividing by a single digit.
+fn div_rem_digit(a: &[u32], divisor: u32) -> (Digits, u32) {
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let current = (rem << DIGIT_BITS) | a[i] as u64;
+        quotient[i] = (current / divisor as u64) as u32;
+        rem = current % divisor as u64;
+    }
+    (normalize(quotient), rem as u32)
+}
+
+fn bit_length(a: &[u32]) -> usize {
+    match a.last() {
+        Some(&top) => a.len() * DIGIT_BITS as usize - top.leading_zeros() as usize,
+        None => 0,
+    }
+}
+
+fn bit(a: &[u32], index: usize) -> bool {
+    a[index / DIGIT_BITS as usize] >> (index % DIGIT_BITS as usize) & 1 != 0
+}
+
+/// Truncating quotient and remainder, `b` must not be zero.
+pub fn div_rem(a: &[u32], b: &[u32]) -> (Digits, Digits) {
+    assert!(!b.is_empty(), "division by zero");
+    if compare(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if b.len() == 1 {
+        let (quotient, rem) = div_rem_digit(a, b[0]);
+        return (quotient, from_u128(rem as u128));
+    }
+
+    // binary long division, one quotient bit at a time
+    let mut quotient = vec![0u32; a.len()];
+    let mut rem: Digits = Vec::new();
+    for i in (0..bit_length(a)).rev() {
+        rem = shl(&rem, 1);
+        if bit(a, i) {
+            if rem.is_empty() {
+                rem.push(1);
+            } else {
+                rem[0] |= 1;
+            }
+        }
+        if compare(&rem, b) != Ordering::Less {
+            rem = sub(&rem, b);
+            quotient[i / DIGIT_BITS as usize] |= 1 << (i % DIGIT_BITS as usize);
+        }
+    }
+    (normalize(quotient), rem)
+}
+
+pub fn shl(a: &[u32], bits: u32) -> Digits {
+    if a.is_empty() {
+        return Vec::new();
+    }
+    let (digits, bits) = ((bits / DIGIT_BITS) as usize, bits % DIGIT_BITS);
+    let mut result = vec![0u32; digits];
+    let mut carry = 0u32;
+    for &digit in a {
+        if bits == 0 {
+            result.push(digit);
+        } else {
+            result.push(digit << bits | carry);
+            carry = digit >> (DIGIT_BITS - bits);
+        }
+    }
+    if carry != 0 {
+        result.push(carry);
+    }
+    result
+}
+
+/// `a >> bits`, together with whether any one bits were shifted out.
+pub fn shr(a: &[u32], bits: u32) -> (Digits, bool) {
+    let (digits, bits) = ((bits / DIGIT_BITS) as usize, bits % DIGIT_BITS);
+    if digits >= a.len() {
+        return (Vec::new(), !a.is_empty());
+    }
+    let mut lost = a[..digits].iter().any(|&digit| digit != 0);
+    if bits != 0 {
+        lost |= a[digits] & ((1 << bits) - 1) != 0;
+    }
+    let mut result = Vec::with_capacity(a.len() - digits);
+    for i in digits..a.len() {
+        let mut digit = a[i] >> bits;
+        if bits != 0 {
+            if let Some(&next) = a.get(i + 1) {
+                digit |= next << (DIGIT_BITS - bits);
+            }
+        }
+        result.push(digit);
+    }
+    (normalize(result), lost)
+}
+
+/// Formats `a` in `radix`, which has to be between 2 and 36.
+pub fn to_string_radix(a: &[u32], radix: u32) -> String {
+    assert!((2..=36).contains(&radix));
+    if a.is_empty() {
+        return "0".to_string();
+    }
+    let mut chars = Vec::new();
+    let mut rest = a.to_vec();
+    while !rest.is_empty() {
+        let (quotient, digit) = div_rem_digit(&rest, radix);
+        chars.push(std::char::from_digit(digit, radix).unwrap());
+        rest = quotient;
+    }
+    chars.iter().rev().collect()
+}
+
+/// Parses digits in `radix` without sign or prefix, `None` if `text` is
+/// empty or contains anything else.
+pub fn from_str_radix(text: &str, radix: u32) -> Option<Digits> {
+    if text.is_empty() {
+        return None;
+    }
+    let mut result: Digits = Vec::new();
+    for c in text.chars() {
+        let digit = c.to_digit(radix)?;
+        result = add(&mul(&result, &[radix]), &from_u128(digit as u128));
+    }
+    Some(normalize(result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bignum_arithmetic() {
+        let a = from_str_radix("123456789012345678901234567890", 10).unwrap();
+        let b = from_str_radix("987654321098765432109876543210", 10).unwrap();
+        let product = mul(&a, &b);
+        assert_eq!(
+            to_string_radix(&product, 10),
+            "121932631137021795226185032733622923332237463801111263526900"
+        );
+        let (quotient, rem) = div_rem(&product, &a);
+        assert_eq!(quotient, b);
+        assert!(rem.is_empty());
+
+        let (quotient, rem) = div_rem(&b, &a);
+        assert_eq!(to_string_radix(&quotient, 10), "8");
+        assert_eq!(to_string_radix(&rem, 10), "9000000000900000000090");
+        assert_eq!(
+            compare(&add(&mul(&quotient, &a), &rem), &b),
+            Ordering::Equal
+        );
+        assert_eq!(sub(&b, &b), Vec::<u32>::new());
+
+        let one = from_u128(1);
+        assert_eq!(
+            to_string_radix(&shl(&one, 100), 16),
+            format!("1{}", "0".repeat(25))
+        );
+        let (shifted, lost) = shr(&shl(&one, 100), 99);
+        assert_eq!(to_u128(&shifted), Some(2));
+        assert!(!lost);
+        assert!(shr(&from_u128(3), 1).1);
+    }
+}
@@ -0,0 +1,131 @@
+//! Pointer compression: heap object fields hold 32 bit offsets from the base
+//! of a 4 GiB cage instead of full pointers.
+//!
+//! Every heap lives in a chunk of the one process wide cage, so a compressed
+//! field can be decompressed without knowing which heap it belongs to. Chunk
+//! 0 is never handed out, which keeps the small odd values (nil and the other
+//! immediates) distinguishable from offsets. Small integers are stored as
+//! their low 32 bits and sign extended when loaded.
+
+use crate::os;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+pub const CAGE_SIZE: usize = 4 * 1024 * 1024 * 1024;
+/// Every heap takes one chunk, see `HEAP_SIZE`. The cage holds
+/// `CHUNKS - 1` heaps at the same time.
+pub const CHUNK_SIZE: usize = 64 * 1024 * 1024;
+/// One bit per chunk has to fit `USED_CHUNKS`.
+const CHUNKS: usize = CAGE_SIZE / CHUNK_SIZE;
+
+static INIT: Once = Once::new();
+static mut CAGE_BASE: usize = 0;
+/// Bit `i` is set while chunk `i` is in use.
+static USED_CHUNKS: AtomicUsize = AtomicUsize::new(1);
+
+pub fn cage_base() -> usize {
+    INIT.call_once(|| unsafe {
+        CAGE_BASE = os::reserve(CAGE_SIZE) as usize;
+    });
+    unsafe { CAGE_BASE }
+}
+
+/// Hands out an unused chunk of the cage, made writable. `size` has to fit
+/// into `CHUNK_SIZE`.
+pub fn allocate_chunk(size: usize) -> *mut u8 {
+    assert!(
+        size <= CHUNK_SIZE,
+        "heap of {} bytes does not fit into a cage chunk",
+        size
+    );
+    let base = cage_base();
+    let mut used = USED_CHUNKS.load(Ordering::Relaxed);
+    loop {
+        let index = (!used).trailing_zeros() as usize;
+        if index >= CHUNKS {
+            panic!("pointer compression cage is full");
+        }
+        match USED_CHUNKS.compare_exchange_weak(
+            used,
+            used | (1 << index),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => {
+                let ptr = (base + index * CHUNK_SIZE) as *mut u8;
+                os::mprotect(ptr, CHUNK_SIZE, os::ProtType::Writable);
+                return ptr;
+            }
+            Err(current) => used = current,
+        }
+    }
+}
+
+/// Returns a chunk obtained from `allocate_chunk` to the cage.
+pub fn release_chunk(ptr: *mut u8) {
+    let index = (ptr as usize - cage_base()) / CHUNK_SIZE;
+    os::decommit(ptr, CHUNK_SIZE);
+    USED_CHUNKS.fetch_and(!(1 << index), Ordering::AcqRel);
+}
+
+#[inline(always)]
+pub fn compress(value: *mut u8) -> u32 {
+    let value = value as usize;
+    if value & 1 == 0 {
+        assert!(
+            value as isize == value as i32 as isize,
+            "small integer does not fit a compressed field"
+        );
+        value as u32
+    } else if value < CHUNK_SIZE {
+        value as u32
+    } else {
+        debug_assert!(value - unsafe { CAGE_BASE } < CAGE_SIZE);
+        (value - unsafe { CAGE_BASE }) as u32
+    }
+}
+
+#[inline(always)]
+pub fn decompress(raw: u32) -> *mut u8 {
+    if raw & 1 == 0 {
+        raw as i32 as isize as *mut u8
+    } else if (raw as usize) < CHUNK_SIZE {
+        raw as usize as *mut u8
+    } else {
+        (unsafe { CAGE_BASE } + raw as usize) as *mut u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc::copying::CopyGC;
+    use crate::heap::{HNumber, MAX_SMALL_INT};
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let chunk = allocate_chunk(CHUNK_SIZE);
+        let object = unsafe { chunk.offset(17) };
+        assert_eq!(decompress(compress(object)), object);
+
+        let nil = 0x01 as *mut u8;
+        assert_eq!(decompress(compress(nil)), nil);
+        let minus_one = (-1isize << 1) as *mut u8;
+        assert_eq!(decompress(compress(minus_one)), minus_one);
+        release_chunk(chunk);
+    }
+
+    #[test]
+    fn test_many_heaps() {
+        let heaps: Vec<CopyGC> = (0..20).map(|_| CopyGC::new()).collect();
+        drop(heaps);
+        let chunk = allocate_chunk(CHUNK_SIZE);
+        release_chunk(chunk);
+    }
+
+    #[test]
+    #[should_panic(expected = "small integer does not fit a compressed field")]
+    fn test_compress_checks_small_integers() {
+        compress(HNumber::tag(MAX_SMALL_INT + 1) as *mut u8);
+    }
+}
@@ -1,14 +1,36 @@
+use super::intern::InternTable;
+use super::pretenure::{AllocationSites, SiteFeedback, SiteId, NO_SITE};
 use super::*;
 use crate::heap::*;
 use crate::mem;
 use crate::os;
 use crate::os::ProtType;
 pub struct CopyGC {
+    memory: *mut u8,
     total: Region,
     separator: Address,
     alloc: alloc::BumpAllocator,
-    grey: Vec<*mut HValue>,
+    old: Region,
+    old_alloc: alloc::BumpAllocator,
+    /// Memory of dead old space objects as `(start, size)`, sorted by address
+    /// and coalesced. Old space is never compacted, so allocations reuse
+    /// these chunks before bumping.
+    old_free: Vec<(Address, usize)>,
+    sites: AllocationSites,
+    grey: Vec<*mut *mut u8>,
     black: Vec<*mut HValue>,
+    roots: Vec<(*mut *mut u8, RefType)>,
+    /// Values of all `Local` handles, see `handles::HandleScope`.
+    pub(super) handles: Vec<*mut u8>,
+    collections: usize,
+    record_statistics: bool,
+    strict_integers: bool,
+    statistics: Vec<stats::HeapStatistics>,
+    pub(super) interned: InternTable,
+    /// Maps keyed by object identity seen during this collection.
+    rehash: Vec<*mut u8>,
+    /// Boxed so the root slot does not move with the collector.
+    root_shape: Box<*mut u8>,
 }
 
 pub struct FormattedSize {
@@ -43,16 +65,41 @@ pub fn formatted_size(size: usize) -> FormattedSize {
     FormattedSize { size }
 }
 
+#[cfg(not(feature = "compressed-pointers"))]
 extern "C" {
     fn malloc(size: usize) -> *mut u8;
+    fn free(ptr: *mut u8);
+}
+
+/// With compressed pointers the heap has to live inside the cage, see
+/// `compress::allocate_chunk`.
+#[cfg(feature = "compressed-pointers")]
+fn allocate_heap(size: usize) -> *mut u8 {
+    compress::allocate_chunk(size)
+}
+
+#[cfg(not(feature = "compressed-pointers"))]
+fn allocate_heap(size: usize) -> *mut u8 {
+    unsafe { malloc(size) }
+}
+
+#[cfg(feature = "compressed-pointers")]
+fn release_heap(ptr: *mut u8) {
+    compress::release_chunk(ptr);
+}
+
+#[cfg(not(feature = "compressed-pointers"))]
+fn release_heap(ptr: *mut u8) {
+    unsafe { free(ptr) }
 }
 
 impl CopyGC {
     pub fn new() -> CopyGC {
         let alignment = 2 * os::page_size() as usize;
         let heap_size = mem::align_usize(HEAP_SIZE, alignment);
+        let old_size = mem::align_usize(OLD_SPACE_SIZE, alignment);
         //let ptr = os::mmap(heap_size, os::ProtType::Writable);
-        let ptr = unsafe { malloc(heap_size) };
+        let ptr = allocate_heap(heap_size + old_size);
         if ptr.is_null() {
             panic!("could not allocate semi space of size {} bytes", heap_size);
         }
@@ -63,60 +110,265 @@ impl CopyGC {
         let semi_size = heap_size / 2;
         let separator = heap_start.offset(1).offset(semi_size);
 
+        // old space follows the semi spaces and is never moved
+        let old_start = heap_start.offset(heap_size);
+        let old = old_start.offset(1).region_start(old_size - 1);
+
         CopyGC {
+            memory: ptr,
             total: heap,
             separator,
             alloc: alloc::BumpAllocator::new(heap_start, separator),
+            old,
+            old_alloc: alloc::BumpAllocator::new(old_start, old.end),
+            old_free: Vec::new(),
+            sites: AllocationSites::new(),
             grey: Vec::new(),
             black: Vec::new(),
+            roots: Vec::new(),
+            handles: Vec::new(),
+            collections: 0,
+            record_statistics: false,
+            strict_integers: false,
+            statistics: Vec::new(),
+            interned: InternTable::new(),
+            rehash: Vec::new(),
+            root_shape: Box::new(std::ptr::null_mut()),
+        }
+    }
+
+    /// Registers `slot` as a root. Persistent roots keep their value alive
+    /// and weak roots are cleared to nil once their value dies, in both cases
+    /// the slot is updated when the value is moved.
+    pub fn add_root(&mut self, slot: *mut *mut u8, kind: RefType) {
+        self.roots.push((slot, kind));
+    }
+
+    pub fn remove_root(&mut self, slot: *mut *mut u8) {
+        if let Some(pos) = self.roots.iter().rposition(|&(root, _)| root == slot) {
+            self.roots.remove(pos);
         }
     }
 
+    pub fn roots(&self) -> &[(*mut *mut u8, RefType)] {
+        &self.roots
+    }
+
+    /// Shape of objects without properties, the root of all shape
+    /// transition trees.
+    pub fn root_shape(&mut self) -> *mut u8 {
+        if self.root_shape.is_null() {
+            *self.root_shape = HShape::new_root(self);
+            let slot: *mut *mut u8 = &mut *self.root_shape;
+            self.add_root(slot, RefType::Persistent);
+        }
+        *self.root_shape
+    }
+
     pub fn process_grey(&mut self, top: &mut Address, from_space: Region) {
-        while !self.grey.is_empty() {
-            let item: *mut HValue = self.grey.remove(0);
-            if HValue::is_unboxed(item as *mut _) || item == HeapTag::Nil as u8 as *mut HValue {
-                continue;
-            }
+        while let Some(slot) = self.grey.pop() {
             unsafe {
-                if !(*item).is_gc_marked() {
-                    if !from_space.contains(Address::from_ptr(item)) {
-                        if !(*item).is_soft_gc_marked() {
-                            (*item).set_soft_gc_mark();
-                            self.black.push(item);
-                            self.visit(item);
-                        }
-                        continue;
-                    }
-                    let addr = self.copy(Address::from_ptr(item), top, from_space);
-                    if !(*item).is_gc_marked() {
-                        (*item).set_gc_mark(addr.to_mut_ptr());
-                    }
+                let value = self.forward(load_field(slot), top, from_space);
+                store_field(slot, value);
+            }
+        }
+    }
+
+    /// Returns the address `value` has after this collection, copying it out
+    /// of from space if that did not happen yet.
+    fn forward(&mut self, value: *mut u8, top: &mut Address, from_space: Region) -> *mut u8 {
+        if !HValue::is_heap_pointer(value) {
+            return value;
+        }
+        let item = HValue::cast(value);
+        let item_addr = Address::from_ptr(item);
+        unsafe {
+            if !from_space.contains(item_addr) {
+                if !self.to_space().contains(item_addr) && !(*item).is_soft_gc_marked() {
+                    (*item).set_soft_gc_mark();
+                    self.black.push(item);
                     self.visit(item);
-                } else {
-                    if !(*item).is_gc_marked() {
-                        (*item).set_gc_mark((*item).get_gc_mark());
-                    }
                 }
+                return value;
+            }
+
+            if (*item).is_gc_marked() {
+                return (*item).get_gc_mark();
             }
+            if (*item).tag() == HeapTag::String && HString::is_cons(value) {
+                if let Some(new) = self.forward_cons(item as *mut HString, top, from_space) {
+                    return new;
+                }
+            }
+
+            let addr = self.copy(item_addr, top, from_space);
+            if self.old.contains(addr) {
+                // promoted objects are live old space objects now
+                (*addr.to_mut_ptr::<HValue>()).set_soft_gc_mark();
+                self.black.push(addr.to_mut_ptr());
+            }
+            self.visit(addr.to_mut_ptr());
+            addr.to_mut_ptr()
+        }
+    }
+
+    /// Cons strings with an empty side are short-circuited to the other
+    /// side, and cons strings deeper than `HString::FLATTEN_CONS_DEPTH` are
+    /// copied as flat strings if to space has room. Returns `None` if
+    /// `string` has to be copied as is.
+    fn forward_cons(
+        &mut self,
+        string: *mut HString,
+        top: &mut Address,
+        from_space: Region,
+    ) -> Option<*mut u8> {
+        unsafe {
+            let cons: &HString = &*string;
+            let target = if HString::static_length(cons.right()) == 0 {
+                cons.left()
+            } else if HString::static_length(cons.left()) == 0 {
+                cons.right()
+            } else {
+                std::ptr::null_mut()
+            };
+            if !target.is_null() {
+                let new = self.forward(target, top, from_space);
+                (*HValue::cast(cons.addr())).set_gc_mark(new);
+                return Some(new);
+            }
+
+            if cons.depth() <= HString::FLATTEN_CONS_DEPTH
+                || cons.length() > HString::MAX_FLATTEN_LENGTH
+            {
+                return None;
+            }
+
+            // same size `HValue::size` reports for the flat string
+            let size = Self::tagged_size(HString::flat_size(cons.length())) + 8;
+            if top.offset(size) > self.to_space().end {
+                return None;
+            }
+            let hval: &HValue = &*HValue::cast(cons.addr());
+            let new = top.to_mut_ptr::<u8>();
+            *top = top.offset(size);
+
+            std::ptr::write_bytes(new.offset(HValue::TAG_OFFSET), 0, size);
+            *new.offset(HValue::TAG_OFFSET) = HeapTag::String as u8;
+            let flat: &HValue = &*HValue::cast(new);
+            flat.set_generation(hval.generation().saturating_add(1));
+            *(new.offset(HString::HASH_OFFSET) as *mut u32) =
+                *(cons.addr().offset(HString::HASH_OFFSET) as *mut u32);
+            *(new.offset(HString::LENGTH_OFFSET) as *mut u32) = cons.length();
+            *(new.offset(HString::CHAR_LENGTH_OFFSET) as *mut u32) = cons.char_length();
+            cons.write_chars(new.offset(HString::VALUE_OFFSET));
+
+            hval.set_gc_mark(new);
+            Some(new)
         }
     }
 
     pub fn alloc_tagged(&mut self, tag: HeapTag, size: usize) -> Address {
+        let size = Self::tagged_size(size);
         let ptr = self.alloc.bump_alloc(size + 8).to_mut_ptr::<u8>();
 
         if !ptr.is_null() {
-            unsafe {
-                *((ptr as isize + HValue::TAG_OFFSET) as *mut u8) = tag as u8;
-            }
-            return Address::from_ptr(ptr);
+            return Self::init_tagged(ptr, tag, size);
         }
 
         println!("alloc_tagged: Collecting garbage");
         self.collect_garbage();
         let ptr = self.alloc.bump_alloc(size + 8).to_mut_ptr::<u8>();
+        if ptr.is_null() {
+            panic!("alloc_tagged: out of memory ({} bytes requested)", size + 8);
+        }
+        Self::init_tagged(ptr, tag, size)
+    }
+
+    /// Same as `alloc_tagged`, but the values in `live` are kept alive and
+    /// updated should the allocation have to collect garbage.
+    pub fn alloc_tagged_keep(
+        &mut self,
+        tag: HeapTag,
+        size: usize,
+        live: &mut [*mut u8],
+    ) -> Address {
+        let count = self.roots.len();
+        for value in live.iter_mut() {
+            self.roots
+                .push((value as *mut *mut u8, RefType::Persistent));
+        }
+        let result = self.alloc_tagged(tag, size);
+        self.roots.truncate(count);
+        result
+    }
+
+    /// Allocates an object for the allocation site `site`. Objects from sites
+    /// whose objects usually survive are allocated in old space directly.
+    pub fn alloc_tagged_at(&mut self, tag: HeapTag, size: usize, site: SiteId) -> Address {
+        let tenured = self.sites.should_tenure(site);
+        let result = if tenured {
+            let size = Self::tagged_size(size);
+            let mut ptr = self.alloc_old(size + 8);
+            if ptr.is_null() {
+                self.collect_garbage();
+                ptr = self.alloc_old(size + 8);
+            }
+            if ptr.is_null() {
+                panic!(
+                    "alloc_tagged_at: old space out of memory ({} bytes requested)",
+                    size + 8
+                );
+            }
+            Self::init_tagged(ptr.to_mut_ptr(), tag, size)
+        } else {
+            self.alloc_tagged(tag, size)
+        };
+
+        self.sites.record_allocation(site, tenured);
+        unsafe {
+            (*result.to_mut_ptr::<HValue>()).set_site(site);
+        }
+        result
+    }
+
+    /// Allocates `size` bytes in old space, from the first free chunk that is
+    /// big enough or else by bumping. Returns null if old space is full.
+    fn alloc_old(&mut self, size: usize) -> Address {
+        let pos = self.old_free.iter().position(|&(_, free)| free >= size);
+        if let Some(pos) = pos {
+            let (start, free) = self.old_free[pos];
+            if free == size {
+                self.old_free.remove(pos);
+            } else {
+                self.old_free[pos] = (start.offset(size), free - size);
+            }
+            return start;
+        }
+        self.old_alloc.bump_alloc(size)
+    }
+
+    /// Registers a new allocation site, there should be one site per
+    /// `AllocateObject` and `AllocateArray` instruction.
+    pub fn new_allocation_site(&mut self) -> SiteId {
+        self.sites.register()
+    }
+
+    pub fn site_feedback(&self, site: SiteId) -> Option<&SiteFeedback> {
+        self.sites.get(site)
+    }
+
+    /// Rounds a payload size up so that headers stay aligned and
+    /// `HValue::size` of the object matches the allocation.
+    fn tagged_size(size: usize) -> usize {
+        mem::align_usize(size, mem::ptr_width_usize())
+    }
+
+    /// Memory is reused after every collection, so header and fields are
+    /// cleared to not pick up stale gc marks or pointers.
+    fn init_tagged(ptr: *mut u8, tag: HeapTag, size: usize) -> Address {
         unsafe {
-            *((ptr as isize + HValue::TAG_OFFSET) as *mut u8) = tag as u8;
+            std::ptr::write_bytes(ptr.offset(HValue::TAG_OFFSET), 0, size + 8);
+            *ptr.offset(HValue::TAG_OFFSET) = tag as u8;
         }
         Address::from_ptr(ptr)
     }
@@ -141,24 +393,31 @@ impl CopyGC {
         let old_size = self.alloc.top().offset_from(from_space.start);
 
         let mut top = to_space.start;
-        let mut scan = top;
 
-        /*
-            for root in rootset {
-
-
-            }
-        */
-        while scan < top {
-            let size = unsafe { (*scan.to_mut_ptr::<HValue>()).size() };
-            unsafe {
-                println!("{:?}", (*scan.to_mut_ptr::<HValue>()).tag());
+        // roots live outside of the heap and always hold full pointers, so
+        // they are forwarded right away instead of going through the grey list
+        for i in 0..self.roots.len() {
+            let (slot, kind) = self.roots[i];
+            if kind == RefType::Persistent {
+                unsafe {
+                    *slot = self.forward(*slot, &mut top, from_space);
+                }
             }
-            self.grey.push(scan.to_mut_ptr::<HValue>());
-            scan = scan.offset(size);
+        }
+        for i in 0..self.handles.len() {
+            self.handles[i] = self.forward(self.handles[i], &mut top, from_space);
         }
 
         self.process_grey(&mut top, from_space);
+        // keys moved, so identity hashes changed
+        while let Some(map) = self.rehash.pop() {
+            unsafe { (*(map as *mut HMap)).rehash() };
+        }
+        self.process_weak_roots(from_space);
+        self.interned.process(from_space, self.old);
+        self.process_tenured_deaths();
+        self.sites.decide();
+        self.sweep_old_space();
 
         while self.black.len() != 0 {
             let value = self.black.remove(0);
@@ -180,6 +439,12 @@ impl CopyGC {
         } else {
             (garbage as f64 / old_size as f64) * 100f64
         };
+        self.collections += 1;
+        if self.record_statistics {
+            let statistics = self.heap_statistics();
+            self.statistics.push(statistics);
+        }
+
         let end = time::PreciseTime::now();
         println!(
             "Copy GC: {:.1} ms, {}->{} size, {}/{:.0}% garbage",
@@ -191,6 +456,142 @@ impl CopyGC {
         );
     }
 
+    /// Updates weak roots after tracing: values that were copied get their
+    /// new address, values that were left behind in from space are dead.
+    fn process_weak_roots(&mut self, from_space: Region) {
+        for &(slot, kind) in self.roots.iter() {
+            if kind != RefType::Weak {
+                continue;
+            }
+            unsafe {
+                let value = *slot as *mut HValue;
+                if !HValue::is_heap_pointer(value as *mut _) {
+                    continue;
+                }
+                if self.old.contains(Address::from_ptr(value)) {
+                    // old space objects are swept in place, see
+                    // `sweep_old_space`
+                    if !(*value).is_soft_gc_marked() {
+                        *slot = HeapTag::Nil as u8 as *mut u8;
+                    }
+                    continue;
+                }
+                if !from_space.contains(Address::from_ptr(value)) {
+                    continue;
+                }
+                *slot = if (*value).is_gc_marked() {
+                    (*value).get_gc_mark()
+                } else {
+                    HeapTag::Nil as u8 as *mut u8
+                };
+            }
+        }
+    }
+
+    /// Pretenured objects are not copied, so to notice that a prediction
+    /// was wrong old space is checked for pretenured objects that were not
+    /// reached during this collection.
+    fn process_tenured_deaths(&mut self) {
+        let mut dead = Vec::new();
+        self.each_old_object(|value| unsafe {
+            let site = (*value).site();
+            if site != NO_SITE && !(*value).is_soft_gc_marked() {
+                dead.push(site);
+                // only report each object once
+                (*value).set_site(NO_SITE);
+            }
+        });
+        for site in dead {
+            self.sites.record_tenured_death(site);
+        }
+    }
+
+    /// Old space objects that were not reached during this collection are
+    /// dead, their memory is added to the free list. Runs before the soft
+    /// marks of live objects are reset.
+    fn sweep_old_space(&mut self) {
+        let mut dead = Vec::new();
+        self.each_old_object(|value| unsafe {
+            if !(*value).is_soft_gc_marked() {
+                dead.push((Address::from_ptr(value), (*value).size()));
+            }
+        });
+        dead.append(&mut self.old_free);
+        dead.sort_unstable_by_key(|&(start, _)| start);
+
+        let mut free: Vec<(Address, usize)> = Vec::new();
+        for (start, size) in dead {
+            match free.last_mut() {
+                Some((last, last_size)) if last.offset(*last_size) == start => *last_size += size,
+                _ => free.push((start, size)),
+            }
+        }
+        // a free chunk at the end goes back to the bump allocator
+        if let Some(&(start, size)) = free.last() {
+            if start.offset(size) == self.old_alloc.top() {
+                free.pop();
+                self.old_alloc.reset(start, self.old.end);
+            }
+        }
+        self.old_free = free;
+    }
+
+    /// Number of collections done so far.
+    pub fn collections(&self) -> usize {
+        self.collections
+    }
+
+    /// Enables recording of `heap_statistics` after every collection.
+    pub fn set_record_statistics(&mut self, record: bool) {
+        self.record_statistics = record;
+    }
+
+    /// In strict integer mode integer arithmetic that overflows the small
+    /// integer range produces BigInts instead of doubles.
+    pub fn set_strict_integers(&mut self, strict: bool) {
+        self.strict_integers = strict;
+    }
+
+    pub fn strict_integers(&self) -> bool {
+        self.strict_integers
+    }
+
+    /// Statistics recorded after every collection since recording was
+    /// enabled, oldest first.
+    pub fn statistics_history(&self) -> &[stats::HeapStatistics] {
+        &self.statistics
+    }
+
+    /// Calls `f` for every object allocated in the current space and in old
+    /// space, walking the spaces linearly using `HValue::size`. Old space
+    /// objects found dead by the last collection are skipped.
+    pub fn each_object<F: FnMut(*mut HValue)>(&self, mut f: F) {
+        Self::walk(self.from_space().start, self.alloc.top(), &mut f);
+        self.each_old_object(f);
+    }
+
+    pub fn each_old_object<F: FnMut(*mut HValue)>(&self, mut f: F) {
+        let mut scan = self.old.start;
+        for &(start, size) in self.old_free.iter() {
+            Self::walk(scan, start, &mut f);
+            scan = start.offset(size);
+        }
+        Self::walk(scan, self.old_alloc.top(), &mut f);
+    }
+
+    fn walk<F: FnMut(*mut HValue)>(start: Address, top: Address, f: &mut F) {
+        let mut scan = start;
+        while scan < top {
+            let value = scan.to_mut_ptr::<HValue>();
+            f(value);
+            scan = scan.offset(unsafe { (*value).size() });
+        }
+    }
+
+    pub fn old_space(&self) -> Region {
+        self.old
+    }
+
     pub fn from_space(&self) -> Region {
         if self.alloc.limit() == self.separator {
             Region::new(self.total.start, self.separator)
@@ -207,15 +608,33 @@ impl CopyGC {
         }
     }
 
-    pub fn copy(&self, from: Address, top: &mut Address, from_space: Region) -> Address {
-        let addr = *top;
+    pub fn copy(&mut self, from: Address, top: &mut Address, from_space: Region) -> Address {
         let hval: &HValue = unsafe { &(*HValue::cast(from.to_mut_ptr())) };
 
         if hval.is_gc_marked() {
             return Address::from_ptr(hval.get_gc_mark());
         }
-        let (_, size) = unsafe { (*HValue::cast(from.to_mut_ptr())).copy_to(top) };
-        *top = top.offset(size);
+
+        let generation = hval.generation();
+        if generation == 0 {
+            self.sites.record_survival(hval.site());
+        }
+
+        // objects that survived long enough are promoted to old space
+        let mut addr = Address::null();
+        if generation + 1 >= MIN_OLD_SPACE_GEN {
+            addr = self.alloc_old(hval.size());
+        }
+        // with old space full the object stays in the nursery until a later
+        // collection frees some
+        if addr.is_null() {
+            addr = *top;
+            *top = top.offset(hval.size());
+        }
+        unsafe {
+            (*HValue::cast(from.to_mut_ptr())).copy_to(&mut addr);
+            (*addr.to_mut_ptr::<HValue>()).set_generation(generation.saturating_add(1));
+        }
 
         hval.set_gc_mark(addr.to_mut_ptr());
 
@@ -223,87 +642,123 @@ impl CopyGC {
     }
 
     pub fn visit(&mut self, value: *mut HValue) {
-        println!("{:?}", unsafe { (*value).tag() });
-        match unsafe { (*value).tag() } {
-            HeapTag::Context => self.visit_ctx(value as *mut _),
-            HeapTag::Function => self.visit_function(value as *mut _),
-            HeapTag::Object => self.visit_obj(value as *mut _),
-            HeapTag::Array => self.visit_obj(value as *mut _),
-            HeapTag::Map => self.visit_obj(value as *mut _),
-            HeapTag::String => {
-                // TODO
-                return;
-            }
-
-            _ => {
-                unsafe {
-                    println!("Skip {:?}", (*value).tag());
-                }
-                return;
+        let grey = &mut self.grey;
+        unsafe {
+            (*value).visit_slots(|_, slot| grey.push(slot));
+            if (*value).tag() == HeapTag::Map && HMap::has_identity_keys(value as *mut u8) {
+                self.rehash.push(value as *mut u8);
             }
         }
     }
+}
 
-    pub fn visit_ctx(&mut self, ctx: *mut HContext) {
-        unsafe {
-            let ctx: &HContext = &*ctx;
+impl Drop for CopyGC {
+    fn drop(&mut self) {
+        release_heap(self.memory);
+    }
+}
 
-            if ctx.has_parent() {
-                self.grey.push(HValue::cast(ctx.parent()));
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
 
-            for i in 0..ctx.slots() {
-                if !ctx.has_slot(i) {
-                    continue;
-                }
-                self.grey.push(ctx.get_slot(i));
-            }
-        }
+    fn flat(gc: &mut CopyGC, s: &str) -> *mut u8 {
+        HString::from_str(gc, s)
     }
 
-    pub fn visit_obj(&mut self, obj: *mut HObject) {
+    fn cons(gc: &mut CopyGC, left: *mut u8, right: *mut u8) -> *mut u8 {
+        let addr = gc
+            .alloc_tagged(HeapTag::String, HString::CONS_SIZE)
+            .to_mut_ptr::<u8>();
         unsafe {
-            let obj: &HObject = &*obj;
-            if obj.proto() != std::ptr::null_mut() {
-                self.grey.push(HValue::cast(obj.proto())); // TODO: This may fail, add weak references
-            }
-            self.grey.push(HValue::cast(obj.map()));
+            *addr.offset(HValue::REPR_OFF) = StrRepr::Cons as u8;
+            let depth = std::cmp::max(
+                (*(left as *mut HString)).depth(),
+                (*(right as *mut HString)).depth(),
+            ) + 1;
+            *(addr.offset(HString::LENGTH_OFFSET) as *mut u32) =
+                HString::static_length(left) + HString::static_length(right);
+            *(addr.offset(HString::CHAR_LENGTH_OFFSET) as *mut u32) =
+                HString::static_char_length(left) + HString::static_char_length(right);
+            *(addr.offset(HString::CONS_DEPTH_OFFSET) as *mut u32) = depth;
+            store_field((*(addr as *mut HString)).left_slot(), left);
+            store_field((*(addr as *mut HString)).right_slot(), right);
         }
+        addr
     }
 
-    pub fn visit_function(&mut self, fun: *mut HFunction) {
-        unsafe {
-            let fun: &HFunction = &*fun;
-            if fun.parent_slot() != std::ptr::null_mut()
-                && fun.parent() != BINDING_CONTEXT_TAG as *mut u8
-            {
-                self.grey.push(HValue::cast(fun.parent()));
-            }
+    #[test]
+    fn test_cons_string_is_traced() {
+        let mut gc = CopyGC::new();
+        let left = flat(&mut gc, "hello, ");
+        let right = flat(&mut gc, "world");
+        let mut root = cons(&mut gc, left, right);
+        gc.add_root(&mut root, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert!(gc.from_space().contains(Address::from_ptr(root)));
+        assert!(HString::is_cons(root));
+        let node: &HString = unsafe { &*(root as *mut HString) };
+        assert!(gc.from_space().contains(Address::from_ptr(node.left())));
+        assert!(gc.from_space().contains(Address::from_ptr(node.right())));
+        assert_eq!(string(root), "hello, world");
+    }
 
-            if fun.root_slot() != std::ptr::null_mut() {
-                self.grey.push(HValue::cast(fun.root()));
-            }
-        }
+    #[test]
+    fn test_cons_string_short_circuit() {
+        let mut gc = CopyGC::new();
+        let left = flat(&mut gc, "hello");
+        let empty = flat(&mut gc, "");
+        let mut root = cons(&mut gc, left, empty);
+        gc.add_root(&mut root, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert!(!HString::is_cons(root));
+        assert_eq!(string(root), "hello");
+        assert_eq!(gc.heap_statistics().tag(HeapTag::String).count, 1);
     }
 
-    pub fn visit_array(&mut self, array: *mut HArray) {
-        unsafe {
-            // Array is object,so we need to cast it to object to get object table
-            let array: &HObject = &*(array as *mut HObject);
-            self.grey.push(HValue::cast(array.map()));
+    #[test]
+    fn test_deep_cons_string_is_flattened() {
+        let mut gc = CopyGC::new();
+        let mut root = flat(&mut gc, "a");
+        let mut expected = String::from("a");
+        for i in 0..HString::FLATTEN_CONS_DEPTH + 1 {
+            let part = if i % 2 == 0 { "b" } else { "c" };
+            let right = flat(&mut gc, part);
+            root = cons(&mut gc, root, right);
+            expected.push_str(part);
         }
+        assert!(unsafe { (*(root as *mut HString)).depth() } > HString::FLATTEN_CONS_DEPTH);
+
+        gc.add_root(&mut root, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert!(!HString::is_cons(root));
+        assert_eq!(string(root), expected);
+        assert_eq!(gc.heap_statistics().tag(HeapTag::String).count, 1);
     }
 
-    pub fn visit_map(&mut self, map: *mut HMap) {
-        unsafe {
-            let map: &HMap = &*map;
-            let size = map.size() << 1;
-            for i in 0..size {
-                if !map.has_slot(i) {
-                    continue;
-                }
-                self.grey.push(map.get_slot(i));
-            }
+    #[test]
+    fn test_flattened_string_keeps_heap_aligned() {
+        let mut gc = CopyGC::new();
+        // an odd length leaves the flat size unaligned with compressed
+        // pointers, the object copied after it has to stay aligned
+        let mut root = flat(&mut gc, "a");
+        for _ in 0..HString::FLATTEN_CONS_DEPTH + 1 {
+            let right = flat(&mut gc, "bc");
+            root = cons(&mut gc, root, right);
         }
+        let mut obj = HObject::new(&mut gc, NIL);
+        gc.add_root(&mut root, RefType::Persistent);
+        gc.add_root(&mut obj, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert!(!HString::is_cons(root));
+        assert_eq!(Address::from_ptr(obj).to_usize() % 8, 1);
+        let statistics = gc.heap_statistics();
+        assert_eq!(statistics.tag(HeapTag::String).count, 1);
+        assert_eq!(statistics.tag(HeapTag::Object).count, 1);
     }
 }
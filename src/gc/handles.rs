@@ -0,0 +1,239 @@
+//! Scoped handles for embedders.
+//!
+//! Raw values held across an allocation are invalidated when the collector
+//! moves objects. A `Local` is an index into the handle stack of the `CopyGC`
+//! instead, the collector treats every handle as a root and updates it.
+//! Handles created in a `HandleScope` are released when the scope is
+//! dropped. A `Local<'s, T>` borrows what its scope borrows, the `CopyGC` or
+//! the enclosing scope, so it can't be used once its scope is gone.
+//!
+//! Scopes dereference to the `CopyGC`, allocate through them and open nested
+//! scopes on them:
+//!
+//! ```ignore
+//! let mut scope = HandleScope::new(&mut gc);
+//! let string = HString::from_str(&mut scope, "value");
+//! let string: Local<HString> = scope.local(string);
+//! scope.collect_garbage();
+//! let moved = string.get(&scope);
+//! ```
+
+use super::copying::CopyGC;
+use crate::heap::*;
+use crate::value::Value;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Handle to a value that survives collections, valid until the scope that
+/// created it is dropped. `Local<HValue>` holds values of any kind.
+pub struct Local<'s, T: HValTrait> {
+    index: usize,
+    marker: PhantomData<(&'s (), T)>,
+}
+
+impl<'s, T: HValTrait> Copy for Local<'s, T> {}
+
+impl<'s, T: HValTrait> Clone for Local<'s, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'s, T: HValTrait> PartialEq for Local<'s, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index
+    }
+}
+
+impl<'s, T: HValTrait> Eq for Local<'s, T> {}
+
+impl<'s, T: HValTrait> fmt::Debug for Local<'s, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Local({:?}#{})", T::TAG, self.index)
+    }
+}
+
+impl<'s, T: HValTrait> Local<'s, T> {
+    fn new(index: usize) -> Local<'s, T> {
+        Local {
+            index,
+            marker: PhantomData,
+        }
+    }
+
+    /// Current raw value of the handle.
+    pub fn get(self, gc: &CopyGC) -> *mut u8 {
+        gc.handles[self.index]
+    }
+
+    pub fn set(self, gc: &mut CopyGC, value: *mut u8) {
+        Self::check(value);
+        gc.handles[self.index] = value;
+    }
+
+    /// The decoded value, sharing this handle.
+    pub fn value(self, gc: &CopyGC) -> Value<'s> {
+        Value::from_local(self.cast(), gc)
+    }
+
+    /// The same handle for another type, the value is not checked.
+    pub(crate) fn cast<U: HValTrait>(self) -> Local<'s, U> {
+        Local::new(self.index)
+    }
+
+    fn check(value: *mut u8) {
+        debug_assert!(
+            T::TAG == HValue::TAG || HValue::get_tag(value) == T::TAG,
+            "{:?} stored in a handle for {:?}",
+            HValue::get_tag(value),
+            T::TAG
+        );
+    }
+}
+
+/// Owns all handles created while it is the innermost scope. Nested scopes
+/// are opened on the enclosing one, which they borrow for `'s`.
+pub struct HandleScope<'s> {
+    gc: &'s mut CopyGC,
+    base: usize,
+}
+
+impl<'s> HandleScope<'s> {
+    pub fn new(gc: &'s mut CopyGC) -> HandleScope<'s> {
+        let base = gc.handles.len();
+        HandleScope { gc, base }
+    }
+
+    /// Creates a handle for `value` in this scope.
+    pub fn local<T: HValTrait>(&mut self, value: *mut u8) -> Local<'s, T> {
+        Local::<T>::check(value);
+        self.gc.handles.push(value);
+        Local::new(self.gc.handles.len() - 1)
+    }
+
+    /// Number of handles owned by this scope.
+    pub fn len(&self) -> usize {
+        self.gc.handles.len() - self.base
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> Drop for HandleScope<'a> {
+    fn drop(&mut self) {
+        self.gc.handles.truncate(self.base);
+    }
+}
+
+impl<'a> Deref for HandleScope<'a> {
+    type Target = CopyGC;
+
+    fn deref(&self) -> &CopyGC {
+        self.gc
+    }
+}
+
+impl<'a> DerefMut for HandleScope<'a> {
+    fn deref_mut(&mut self) -> &mut CopyGC {
+        self.gc
+    }
+}
+
+/// Scope that can hand one of its handles to the enclosing scope `parent`.
+/// The slot for it is reserved in `parent` when this scope is created, and
+/// released with `parent`.
+pub struct EscapableHandleScope<'s, 'p> {
+    scope: HandleScope<'s>,
+    slot: usize,
+    escaped: bool,
+    parent: PhantomData<&'p ()>,
+}
+
+impl<'s, 'p> EscapableHandleScope<'s, 'p> {
+    pub fn new(parent: &'s mut HandleScope<'p>) -> EscapableHandleScope<'s, 'p> {
+        let slot = parent.gc.handles.len();
+        parent.gc.handles.push(NIL);
+        EscapableHandleScope {
+            scope: HandleScope::new(parent),
+            slot,
+            escaped: false,
+            parent: PhantomData,
+        }
+    }
+
+    /// Copies `local` into the reserved slot of the enclosing scope, can only
+    /// be done once.
+    pub fn escape<T: HValTrait>(&mut self, local: Local<'s, T>) -> Local<'p, T> {
+        assert!(!self.escaped, "a handle scope can only escape one value");
+        self.escaped = true;
+        let value = local.get(&self.scope);
+        self.scope.gc.handles[self.slot] = value;
+        Local::new(self.slot)
+    }
+}
+
+impl<'s, 'p> Deref for EscapableHandleScope<'s, 'p> {
+    type Target = HandleScope<'s>;
+
+    fn deref(&self) -> &HandleScope<'s> {
+        &self.scope
+    }
+}
+
+impl<'s, 'p> DerefMut for EscapableHandleScope<'s, 'p> {
+    fn deref_mut(&mut self) -> &mut HandleScope<'s> {
+        &mut self.scope
+    }
+}
+
+impl CopyGC {
+    /// Number of live handles of all scopes.
+    pub fn handle_count(&self) -> usize {
+        self.handles.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_pair<'p>(parent: &mut HandleScope<'p>) -> Local<'p, HArray> {
+        let mut scope = EscapableHandleScope::new(parent);
+        let first = HString::from_str(&mut scope, "first");
+        let first: Local<HString> = scope.local(first);
+        let array = HArray::new(&mut scope, 0);
+        let array: Local<HArray> = scope.local(array);
+        scope.collect_garbage();
+
+        let (value, target) = (first.get(&scope), array.get(&scope));
+        let pushed = HArray::push(&mut scope, target, value).unwrap();
+        array.set(&mut scope, pushed);
+        scope.escape(array)
+    }
+
+    #[test]
+    fn test_handle_scopes() {
+        let mut gc = CopyGC::new();
+        let mut scope = HandleScope::new(&mut gc);
+        let pair = make_pair(&mut scope);
+        assert_eq!(scope.len(), 1);
+
+        let before = pair.get(&scope);
+        scope.collect_garbage();
+        let array = pair.get(&scope);
+        assert_ne!(array, before);
+        assert_eq!(HArray::length(array, false), 1);
+        let first = HArray::get(array, 0);
+        assert_eq!(
+            unsafe { (*(first as *mut HString)).to_rust_string() },
+            "first"
+        );
+        assert!(pair.value(&scope).as_array().is_some());
+
+        drop(scope);
+        assert_eq!(gc.handle_count(), 0);
+    }
+}
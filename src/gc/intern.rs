@@ -0,0 +1,152 @@
+use super::copying::CopyGC;
+use super::{Address, Region};
+use crate::heap::*;
+use std::collections::HashMap;
+
+/// VM wide table of interned strings, bucketed by string hash.
+///
+/// Strings are held weakly: the collector updates entries that were copied
+/// and drops the ones that died, see `InternTable::process`.
+#[derive(Default)]
+pub struct InternTable {
+    buckets: HashMap<u32, Vec<*mut u8>>,
+    count: usize,
+}
+
+impl InternTable {
+    pub fn new() -> InternTable {
+        InternTable::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn find(&self, hash: u32, bytes: &[u8]) -> Option<*mut u8> {
+        self.buckets.get(&hash).and_then(|bucket| {
+            bucket
+                .iter()
+                .cloned()
+                .find(|&string| unsafe { (*(string as *mut HString)).bytes() == bytes })
+        })
+    }
+
+    fn insert(&mut self, hash: u32, string: *mut u8) {
+        HString::set_interned(string);
+        self.buckets.entry(hash).or_default().push(string);
+        self.count += 1;
+    }
+
+    /// Called by the collector after tracing: entries that were copied get
+    /// their new address, entries left behind in from space or not reached in
+    /// old space are dead.
+    pub fn process(&mut self, from_space: Region, old_space: Region) {
+        let mut count = 0;
+        self.buckets.retain(|_, bucket| {
+            bucket.retain_mut(|string| unsafe {
+                let value = *string as *mut HValue;
+                if old_space.contains(Address::from_ptr(value)) {
+                    return (*value).is_soft_gc_marked();
+                }
+                if !from_space.contains(Address::from_ptr(value)) {
+                    return true;
+                }
+                if (*value).is_gc_marked() {
+                    *string = (*value).get_gc_mark();
+                    return true;
+                }
+                false
+            });
+            count += bucket.len();
+            !bucket.is_empty()
+        });
+        self.count = count;
+    }
+}
+
+impl CopyGC {
+    /// Returns the interned string with the contents of `string`, interning
+    /// `string` (flattened first) if there is none yet.
+    pub fn intern(&mut self, string: *mut u8) -> *mut u8 {
+        if HString::is_interned(string) {
+            return string;
+        }
+        let string = HString::flatten(self, string);
+        let flat = unsafe { &*(string as *mut HString) };
+        let hash = flat.hash();
+        if let Some(interned) = self.interned.find(hash, &flat.bytes()) {
+            return interned;
+        }
+        // a sliced string would keep its whole parent alive
+        let string = if HString::is_sliced(string) {
+            let copy = HString::from_str(self, &flat.to_rust_string());
+            unsafe {
+                *(copy.offset(HString::HASH_OFFSET) as *mut u32) = hash;
+            }
+            copy
+        } else {
+            string
+        };
+        self.interned.insert(hash, string);
+        string
+    }
+
+    /// Returns the interned string with contents `value`, allocating it only
+    /// if it is not interned yet.
+    pub fn intern_str(&mut self, value: &str) -> *mut u8 {
+        let hash = HString::hash_bytes(value.as_bytes());
+        if let Some(interned) = self.interned.find(hash, value.as_bytes()) {
+            return interned;
+        }
+        let string = HString::from_str(self, value);
+        unsafe {
+            *(string.offset(HString::HASH_OFFSET) as *mut u32) = hash;
+        }
+        self.interned.insert(hash, string);
+        string
+    }
+
+    pub fn intern_table(&self) -> &InternTable {
+        &self.interned
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_shares_strings() {
+        let mut gc = CopyGC::new();
+        let a = gc.intern_str("length");
+        let b = gc.intern_str("length");
+        assert_eq!(a, b);
+        assert!(HString::is_interned(a));
+
+        let left = HString::from_str(&mut gc, "len");
+        let right = HString::from_str(&mut gc, "gth");
+        let concat = HString::concat(&mut gc, left, right);
+        assert_eq!(gc.intern(concat), a);
+        assert!(!HString::is_interned(concat));
+        assert_eq!(gc.intern_table().len(), 1);
+    }
+
+    #[test]
+    fn test_intern_table_is_weak() {
+        let mut gc = CopyGC::new();
+        let mut kept = gc.intern_str("kept");
+        gc.intern_str("dropped");
+        gc.add_root(&mut kept, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert_eq!(gc.intern_table().len(), 1);
+        assert_eq!(gc.intern_str("kept"), kept);
+        assert_eq!(gc.intern_table().len(), 1);
+        gc.intern_str("dropped");
+        assert_eq!(gc.intern_table().len(), 2);
+    }
+}
@@ -1,11 +1,27 @@
 pub mod alloc;
+#[cfg(feature = "compressed-pointers")]
+pub mod compress;
 pub mod copying;
+pub mod handles;
+pub mod intern;
+pub mod pretenure;
+pub mod snapshot;
+pub mod stats;
 use std::cmp::Ordering;
 use std::fmt;
 
 pub const K: usize = 1024;
 pub const M: usize = K * K;
+#[cfg(not(feature = "compressed-pointers"))]
 pub const HEAP_SIZE: usize = 128 * M;
+#[cfg(not(feature = "compressed-pointers"))]
+pub const OLD_SPACE_SIZE: usize = 64 * M;
+/// With compressed pointers fields are half as big, and both spaces have to
+/// fit one chunk of the cage, see `compress::CHUNK_SIZE`.
+#[cfg(feature = "compressed-pointers")]
+pub const HEAP_SIZE: usize = 32 * M;
+#[cfg(feature = "compressed-pointers")]
+pub const OLD_SPACE_SIZE: usize = 16 * M;
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Address(usize);
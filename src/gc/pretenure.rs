@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+/// Identifies an allocation site, i.e. one `AllocateObject` or
+/// `AllocateArray` LIR instruction. Site 0 is reserved for allocations that
+/// are not tracked.
+pub type SiteId = u32;
+
+pub const NO_SITE: SiteId = 0;
+
+/// Minimal number of nursery allocations at a site before a decision is made.
+pub const MIN_SITE_ALLOCATIONS: usize = 100;
+/// Share of objects that have to survive their first collection for a site
+/// to be pretenured.
+pub const TENURE_SURVIVAL_RATIO: f64 = 0.85;
+/// Share of pretenured objects found dead that resets a site's feedback.
+pub const RESET_DEAD_RATIO: f64 = 0.5;
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
+pub enum PretenureDecision {
+    Undecided,
+    Tenure,
+    DontTenure,
+}
+
+/// Survival feedback collected for one allocation site.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SiteFeedback {
+    pub decision: PretenureDecision,
+    /// Objects allocated in the nursery since the last decision.
+    pub allocated: usize,
+    /// Nursery objects that survived their first collection.
+    pub survived: usize,
+    /// Objects allocated directly in old space since the site was tenured.
+    pub tenured: usize,
+    /// Directly allocated old space objects that were found dead.
+    pub tenured_dead: usize,
+}
+
+impl SiteFeedback {
+    fn new() -> SiteFeedback {
+        SiteFeedback {
+            decision: PretenureDecision::Undecided,
+            allocated: 0,
+            survived: 0,
+            tenured: 0,
+            tenured_dead: 0,
+        }
+    }
+
+    /// Survival rate of nursery objects allocated at this site.
+    pub fn survival_ratio(&self) -> f64 {
+        if self.allocated == 0 {
+            return 0f64;
+        }
+        self.survived as f64 / self.allocated as f64
+    }
+
+    fn decide(&mut self) {
+        match self.decision {
+            PretenureDecision::Tenure => {
+                if self.tenured >= MIN_SITE_ALLOCATIONS
+                    && self.tenured_dead as f64 >= self.tenured as f64 * RESET_DEAD_RATIO
+                {
+                    // prediction was wrong, start over collecting feedback
+                    *self = SiteFeedback::new();
+                }
+            }
+            _ => {
+                if self.allocated >= MIN_SITE_ALLOCATIONS {
+                    self.decision = if self.survival_ratio() >= TENURE_SURVIVAL_RATIO {
+                        PretenureDecision::Tenure
+                    } else {
+                        PretenureDecision::DontTenure
+                    };
+                    self.allocated = 0;
+                    self.survived = 0;
+                }
+            }
+        }
+    }
+}
+
+/// Per allocation site survival feedback, used to allocate objects from
+/// sites whose objects always survive directly in old space instead of
+/// copying them through the nursery.
+pub struct AllocationSites {
+    sites: HashMap<SiteId, SiteFeedback>,
+    next: SiteId,
+}
+
+impl AllocationSites {
+    pub fn new() -> AllocationSites {
+        AllocationSites {
+            sites: HashMap::new(),
+            next: NO_SITE + 1,
+        }
+    }
+
+    pub fn register(&mut self) -> SiteId {
+        let site = self.next;
+        self.next += 1;
+        self.sites.insert(site, SiteFeedback::new());
+        site
+    }
+
+    pub fn get(&self, site: SiteId) -> Option<&SiteFeedback> {
+        self.sites.get(&site)
+    }
+
+    pub fn should_tenure(&self, site: SiteId) -> bool {
+        self.sites
+            .get(&site)
+            .map(|feedback| feedback.decision == PretenureDecision::Tenure)
+            .unwrap_or(false)
+    }
+
+    pub fn record_allocation(&mut self, site: SiteId, tenured: bool) {
+        if let Some(feedback) = self.sites.get_mut(&site) {
+            if tenured {
+                feedback.tenured += 1;
+            } else {
+                feedback.allocated += 1;
+            }
+        }
+    }
+
+    pub fn record_survival(&mut self, site: SiteId) {
+        if let Some(feedback) = self.sites.get_mut(&site) {
+            feedback.survived += 1;
+        }
+    }
+
+    pub fn record_tenured_death(&mut self, site: SiteId) {
+        if let Some(feedback) = self.sites.get_mut(&site) {
+            feedback.tenured_dead += 1;
+        }
+    }
+
+    /// Updates pretenuring decisions of all sites, called after every
+    /// collection.
+    pub fn decide(&mut self) {
+        for feedback in self.sites.values_mut() {
+            feedback.decide();
+        }
+    }
+}
+
+impl Default for AllocationSites {
+    fn default() -> AllocationSites {
+        AllocationSites::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::copying::CopyGC;
+    use super::super::Address;
+    use super::*;
+    use crate::heap::*;
+
+    #[test]
+    fn test_pretenure_long_lived_site() {
+        let mut gc = CopyGC::new();
+        let site = gc.new_allocation_site();
+        let mut objects = Vec::new();
+        for _ in 0..MIN_SITE_ALLOCATIONS {
+            let obj = gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site);
+            objects.push(obj.to_mut_ptr::<u8>());
+        }
+        for obj in objects.iter_mut() {
+            gc.add_root(obj, RefType::Persistent);
+        }
+        gc.collect_garbage();
+
+        let feedback = *gc.site_feedback(site).unwrap();
+        assert_eq!(feedback.decision, PretenureDecision::Tenure);
+
+        let obj = gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site);
+        assert!(gc.old_space().contains(obj));
+        assert!(!gc.from_space().contains(obj));
+        unsafe {
+            assert_eq!((*obj.to_mut_ptr::<HValue>()).site(), site);
+        }
+    }
+
+    #[test]
+    fn test_dead_tenured_objects_are_reclaimed() {
+        let mut gc = CopyGC::new();
+        let site = gc.new_allocation_site();
+        let mut objects = Vec::new();
+        for _ in 0..MIN_SITE_ALLOCATIONS {
+            let obj = gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site);
+            objects.push(obj.to_mut_ptr::<u8>());
+        }
+        for obj in objects.iter_mut() {
+            gc.add_root(obj, RefType::Persistent);
+        }
+        gc.collect_garbage();
+        assert_eq!(
+            gc.site_feedback(site).unwrap().decision,
+            PretenureDecision::Tenure
+        );
+
+        let mut dead = gc
+            .alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site)
+            .to_mut_ptr::<u8>();
+        let mut live = gc
+            .alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site)
+            .to_mut_ptr::<u8>();
+        let addr = dead;
+        gc.add_root(&mut dead, RefType::Weak);
+        gc.add_root(&mut live, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert_eq!(dead, NIL);
+        let mut found = false;
+        gc.each_object(|value| found |= value as *mut u8 == addr);
+        assert!(!found);
+        // the next allocation of the same size reuses the memory
+        let obj = gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site);
+        assert_eq!(obj.to_mut_ptr::<u8>(), addr);
+        assert!(gc.old_space().contains(Address::from_ptr(live)));
+    }
+
+    #[test]
+    fn test_short_lived_site_and_reset() {
+        let mut gc = CopyGC::new();
+        let short = gc.new_allocation_site();
+        for _ in 0..MIN_SITE_ALLOCATIONS {
+            gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, short);
+        }
+        gc.collect_garbage();
+        let feedback = *gc.site_feedback(short).unwrap();
+        assert_eq!(feedback.decision, PretenureDecision::DontTenure);
+        let obj = gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, short);
+        assert!(gc.from_space().contains(obj));
+
+        // a tenured site whose objects die gets its feedback reset
+        let site = gc.new_allocation_site();
+        let mut objects = Vec::new();
+        for _ in 0..MIN_SITE_ALLOCATIONS {
+            objects.push(
+                gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site)
+                    .to_mut_ptr::<u8>(),
+            );
+        }
+        for obj in objects.iter_mut() {
+            gc.add_root(obj, RefType::Persistent);
+        }
+        gc.collect_garbage();
+        assert!(gc.site_feedback(site).unwrap().decision == PretenureDecision::Tenure);
+
+        for _ in 0..MIN_SITE_ALLOCATIONS {
+            gc.alloc_tagged_at(HeapTag::Object, 3 * FIELD_SIZE, site);
+        }
+        gc.collect_garbage();
+        let feedback = *gc.site_feedback(site).unwrap();
+        assert_eq!(feedback.decision, PretenureDecision::Undecided);
+        assert_eq!(feedback.tenured, 0);
+    }
+
+    #[test]
+    fn test_promotion() {
+        let mut gc = CopyGC::new();
+        let mut obj = gc
+            .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+            .to_mut_ptr::<u8>();
+        gc.add_root(&mut obj, RefType::Persistent);
+        for _ in 1..MIN_OLD_SPACE_GEN {
+            gc.collect_garbage();
+            assert!(gc.from_space().contains(Address::from_ptr(obj)));
+        }
+        gc.collect_garbage();
+        assert!(gc.old_space().contains(Address::from_ptr(obj)));
+        assert_eq!(
+            unsafe { (*HValue::cast(obj)).generation() },
+            MIN_OLD_SPACE_GEN
+        );
+
+        // promoted objects stay where they are
+        gc.collect_garbage();
+        assert!(gc.old_space().contains(Address::from_ptr(obj)));
+    }
+}
@@ -0,0 +1,248 @@
+use super::copying::CopyGC;
+use crate::heap::*;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// An object recorded in a `HeapSnapshot` together with its outgoing edges.
+#[derive(Clone, Debug)]
+pub struct HeapNode {
+    pub addr: usize,
+    pub tag: HeapTag,
+    pub size: usize,
+    pub edges: Vec<(SlotName, usize)>,
+}
+
+/// A root of a `HeapSnapshot`: the root slot, its kind and the object it holds.
+#[derive(Copy, Clone, Debug)]
+pub struct HeapRoot {
+    pub slot: usize,
+    pub kind: RefType,
+    pub target: usize,
+}
+
+/// The object graph reachable from the collector roots at the time of capture.
+///
+/// Edges are found with `HValue::visit_slots`, the same enumeration the
+/// collector traces with, so the graph matches what keeps objects alive.
+#[derive(Clone, Debug, Default)]
+pub struct HeapSnapshot {
+    pub roots: Vec<HeapRoot>,
+    pub nodes: HashMap<usize, HeapNode>,
+}
+
+/// One step of a `RetainingPath`: `holder` references the next object
+/// through its slot `slot`.
+#[derive(Copy, Clone, Debug)]
+pub struct RetainingStep {
+    pub holder: usize,
+    pub tag: HeapTag,
+    pub slot: SlotName,
+}
+
+/// Shortest chain of references from a root to `target`.
+#[derive(Clone, Debug)]
+pub struct RetainingPath {
+    pub root: HeapRoot,
+    pub steps: Vec<RetainingStep>,
+    pub target: usize,
+    pub target_tag: HeapTag,
+}
+
+impl HeapSnapshot {
+    pub fn capture(gc: &CopyGC) -> HeapSnapshot {
+        let mut snapshot = HeapSnapshot::default();
+        let mut queue = VecDeque::new();
+
+        for &(slot, kind) in gc.roots() {
+            if kind != RefType::Persistent {
+                continue;
+            }
+            let target = unsafe { *slot };
+            if !HValue::is_heap_pointer(target) {
+                continue;
+            }
+            snapshot.roots.push(HeapRoot {
+                slot: slot as usize,
+                kind,
+                target: target as usize,
+            });
+            queue.push_back(target);
+        }
+
+        while let Some(addr) = queue.pop_front() {
+            if snapshot.nodes.contains_key(&(addr as usize)) {
+                continue;
+            }
+            let value: &HValue = unsafe { &*HValue::cast(addr) };
+            let mut node = HeapNode {
+                addr: addr as usize,
+                tag: value.tag(),
+                size: value.size(),
+                edges: Vec::new(),
+            };
+            value.visit_slots(|name, slot| {
+                let target = unsafe { load_field(slot) };
+                if HValue::is_heap_pointer(target) {
+                    node.edges.push((name, target as usize));
+                    queue.push_back(target);
+                }
+            });
+            snapshot.nodes.insert(node.addr, node);
+        }
+
+        snapshot
+    }
+
+    /// Answers "why is this object alive?": returns the shortest chain of
+    /// references from any persistent root to `target`, or `None` if `target`
+    /// is not reachable.
+    pub fn retaining_path(&self, target: *mut u8) -> Option<RetainingPath> {
+        let target = target as usize;
+        let target_tag = self.nodes.get(&target)?.tag;
+
+        // Breadth first search from all roots at once, `parents` remembers
+        // the edge every object was first reached through.
+        let mut parents: HashMap<usize, Option<(usize, SlotName)>> = HashMap::new();
+        let mut first_root: HashMap<usize, HeapRoot> = HashMap::new();
+        let mut queue = VecDeque::new();
+
+        for root in self.roots.iter() {
+            if parents.contains_key(&root.target) {
+                continue;
+            }
+            parents.insert(root.target, None);
+            first_root.insert(root.target, *root);
+            queue.push_back(root.target);
+        }
+
+        while let Some(addr) = queue.pop_front() {
+            if addr == target {
+                break;
+            }
+            let node = match self.nodes.get(&addr) {
+                Some(node) => node,
+                None => continue,
+            };
+            for &(name, next) in node.edges.iter() {
+                if parents.contains_key(&next) {
+                    continue;
+                }
+                parents.insert(next, Some((addr, name)));
+                queue.push_back(next);
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut current = target;
+        while let Some((holder, slot)) = *parents.get(&current)? {
+            steps.push(RetainingStep {
+                holder,
+                tag: self.nodes[&holder].tag,
+                slot,
+            });
+            current = holder;
+        }
+        steps.reverse();
+
+        Some(RetainingPath {
+            root: first_root[&current],
+            steps,
+            target,
+            target_tag,
+        })
+    }
+}
+
+impl fmt::Display for RefType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefType::Weak => write!(f, "weak"),
+            RefType::Persistent => write!(f, "persistent"),
+        }
+    }
+}
+
+impl fmt::Display for RetainingPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} root 0x{:x}", self.root.kind, self.root.slot)?;
+        for step in self.steps.iter() {
+            write!(f, " -> {:?}@0x{:x}.{}", step.tag, step.holder, step.slot)?;
+        }
+        write!(f, " -> {:?}@0x{:x}", self.target_tag, self.target)
+    }
+}
+
+impl CopyGC {
+    pub fn heap_snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot::capture(self)
+    }
+
+    /// Shortest chain of references from a root to `target`, see
+    /// `HeapSnapshot::retaining_path`.
+    pub fn retaining_path(&self, target: *mut u8) -> Option<RetainingPath> {
+        self.heap_snapshot().retaining_path(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn new_context(gc: &mut CopyGC, slots: u32) -> *mut u8 {
+        HContext::new(gc, NIL, slots)
+    }
+
+    unsafe fn new_object(gc: &mut CopyGC) -> *mut u8 {
+        let obj = gc
+            .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+            .to_mut_ptr::<u8>();
+        HObject::set_map_s(obj, HeapTag::Nil as u8 as *mut u8);
+        HObject::set_proto_s(obj, NIL);
+        obj
+    }
+
+    #[test]
+    fn test_retaining_path() {
+        let mut gc = CopyGC::new();
+        unsafe {
+            let mut root = new_context(&mut gc, 3);
+            let holder = new_object(&mut gc);
+            let target = new_object(&mut gc);
+            let garbage = new_object(&mut gc);
+
+            (*(root as *mut HContext)).set_slot(2, holder);
+            HObject::set_proto_s(holder, target);
+            HObject::set_proto_s(garbage, target);
+
+            gc.add_root(&mut root, RefType::Persistent);
+            let path = gc.retaining_path(target).unwrap();
+
+            assert_eq!(path.root.target, root as usize);
+            assert_eq!(path.steps.len(), 2);
+            assert_eq!(path.steps[0].holder, root as usize);
+            assert_eq!(path.steps[0].slot, SlotName::Context(2));
+            assert_eq!(path.steps[1].holder, holder as usize);
+            assert_eq!(path.steps[1].slot, SlotName::Proto);
+            assert!(gc.retaining_path(garbage).is_none());
+        }
+    }
+
+    #[test]
+    fn test_retaining_path_after_gc() {
+        let mut gc = CopyGC::new();
+        unsafe {
+            let mut root = new_context(&mut gc, 1);
+            let obj = new_object(&mut gc);
+            (*(root as *mut HContext)).set_slot(0, obj);
+
+            gc.add_root(&mut root, RefType::Persistent);
+            gc.collect_garbage();
+
+            let obj = (*(root as *mut HContext)).get_slot(0) as *mut u8;
+            assert_eq!(HValue::get_tag(obj), HeapTag::Object);
+            let path = gc.retaining_path(obj).unwrap();
+            assert_eq!(path.steps.len(), 1);
+            assert_eq!(path.steps[0].slot, SlotName::Context(0));
+        }
+    }
+}
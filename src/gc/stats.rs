@@ -0,0 +1,133 @@
+use super::copying::{formatted_size, CopyGC};
+use crate::heap::*;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Number of objects and bytes they occupy, header included.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ObjectStats {
+    pub count: usize,
+    pub bytes: usize,
+}
+
+impl ObjectStats {
+    fn add(&mut self, bytes: usize) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Per `HeapTag` (and per `StrRepr` for strings) accounting of the heap.
+#[derive(Clone, Debug, Default)]
+pub struct HeapStatistics {
+    /// Number of collections done before these statistics were taken.
+    pub collections: usize,
+    pub total: ObjectStats,
+    pub by_tag: BTreeMap<HeapTag, ObjectStats>,
+    pub strings: BTreeMap<StrRepr, ObjectStats>,
+}
+
+impl HeapStatistics {
+    pub fn tag(&self, tag: HeapTag) -> ObjectStats {
+        self.by_tag.get(&tag).cloned().unwrap_or_default()
+    }
+
+    pub fn string_repr(&self, repr: StrRepr) -> ObjectStats {
+        self.strings.get(&repr).cloned().unwrap_or_default()
+    }
+}
+
+impl fmt::Display for HeapStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Heap after {} collections:", self.collections)?;
+        for (tag, stats) in self.by_tag.iter() {
+            writeln!(
+                f,
+                "  {:<12} {:>8} objects {:>8}",
+                format!("{:?}", tag),
+                stats.count,
+                formatted_size(stats.bytes).to_string()
+            )?;
+        }
+        for (repr, stats) in self.strings.iter() {
+            writeln!(
+                f,
+                "  {:<12} {:>8} objects {:>8}",
+                format!("String/{:?}", repr),
+                stats.count,
+                formatted_size(stats.bytes).to_string()
+            )?;
+        }
+        write!(
+            f,
+            "  {:<12} {:>8} objects {:>8}",
+            "Total",
+            self.total.count,
+            formatted_size(self.total.bytes).to_string()
+        )
+    }
+}
+
+impl CopyGC {
+    /// Walks the live space and counts objects and bytes per `HeapTag`.
+    ///
+    /// Objects that became garbage since the last collection are still
+    /// counted, call this right after `collect_garbage` to only see live
+    /// objects.
+    pub fn heap_statistics(&self) -> HeapStatistics {
+        let mut statistics = HeapStatistics {
+            collections: self.collections(),
+            ..Default::default()
+        };
+
+        self.each_object(|value| {
+            let value: &HValue = unsafe { &*value };
+            let size = value.size();
+            let tag = value.tag();
+
+            statistics.total.add(size);
+            statistics.by_tag.entry(tag).or_default().add(size);
+            if tag == HeapTag::String {
+                let repr = unsafe { (*value.as_::<HString>()).repr() };
+                statistics.strings.entry(repr).or_default().add(size);
+            }
+        });
+
+        statistics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_heap_statistics() {
+        let mut gc = CopyGC::new();
+        let object_size = crate::mem::align_usize(8 + 3 * FIELD_SIZE, 8);
+        let mut root = gc
+            .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+            .to_mut_ptr::<u8>();
+        gc.alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE);
+        gc.alloc_tagged(HeapTag::Number, 8);
+
+        let statistics = gc.heap_statistics();
+        assert_eq!(statistics.tag(HeapTag::Object).count, 2);
+        assert_eq!(statistics.tag(HeapTag::Object).bytes, 2 * object_size);
+        assert_eq!(statistics.tag(HeapTag::Number).count, 1);
+        assert_eq!(statistics.tag(HeapTag::Map).count, 0);
+        assert_eq!(statistics.total.count, 3);
+        assert_eq!(statistics.total.bytes, 2 * object_size + 16);
+
+        gc.add_root(&mut root, RefType::Persistent);
+        gc.set_record_statistics(true);
+        gc.collect_garbage();
+
+        let history = gc.statistics_history();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].collections, 1);
+        assert_eq!(history[0].total.count, 1);
+        assert_eq!(history[0].tag(HeapTag::Object).count, 1);
+        assert_eq!(history[0].tag(HeapTag::Number).count, 0);
+    }
+}
@@ -1,3 +1,10 @@
+use crate::bignum::{self, Digits};
+use crate::gc::copying::CopyGC;
+use crate::utils::{compute_hash, hash_word};
+use std::borrow::Cow;
+use std::cell::UnsafeCell;
+use std::cmp::Ordering;
+
 extern "C" {
     fn malloc(x: usize) -> *mut u8;
 }
@@ -124,7 +131,22 @@ pub enum HeapTag {
     Function,
     ExternData,
     Map,
+    Shape,
+    Elements,
+    BigInt,
+    Undefined,
 }
+
+// Immediates are small odd values below any heap address, so they never
+// allocate and are compared by identity.
+pub const NIL: *mut u8 = HeapTag::Nil as u8 as *mut u8;
+pub const UNDEFINED: *mut u8 = 0x03 as *mut u8;
+pub const FALSE: *mut u8 = 0x05 as *mut u8;
+pub const TRUE: *mut u8 = 0x07 as *mut u8;
+/// Missing elements in array storage, and the result of compiled code that
+/// threw. Array reads return `UNDEFINED` for missing elements instead.
+pub const HOLE: *mut u8 = 0x09 as *mut u8;
+pub const MAX_IMMEDIATE: usize = 0x0f;
 #[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
 #[repr(u8)]
 pub enum Tenure {
@@ -147,6 +169,48 @@ pub enum RefType {
     Persistent,
 }
 
+/// Names a pointer slot inside of a heap object.
+///
+/// Slots are reported by `HValue::visit_slots`, which is what the collector
+/// uses to find references, so heap inspection tools can describe an edge
+/// exactly as the collector sees it.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
+pub enum SlotName {
+    Parent,
+    Root,
+    Map,
+    Proto,
+    Context(u32),
+    MapKey(u32),
+    MapValue(u32),
+    Left,
+    Right,
+    Slot(u32),
+    Key,
+    Transitions,
+    Elements,
+}
+
+impl std::fmt::Display for SlotName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SlotName::Parent => write!(f, "parent"),
+            SlotName::Root => write!(f, "root"),
+            SlotName::Map => write!(f, "map"),
+            SlotName::Proto => write!(f, "proto"),
+            SlotName::Context(idx) => write!(f, "[{}]", idx),
+            SlotName::MapKey(idx) => write!(f, "key #{}", idx),
+            SlotName::MapValue(idx) => write!(f, "value #{}", idx),
+            SlotName::Left => write!(f, "left"),
+            SlotName::Right => write!(f, "right"),
+            SlotName::Slot(idx) => write!(f, "slot #{}", idx),
+            SlotName::Key => write!(f, "key"),
+            SlotName::Transitions => write!(f, "transitions"),
+            SlotName::Elements => write!(f, "elements"),
+        }
+    }
+}
+
 pub const MIN_OLD_SPACE_GEN: u8 = 5;
 pub const MIN_FACTORY_SIZE: u8 = 128;
 pub const ENTER_FRAME_TAG: usize = 0xFEEDBEEE;
@@ -154,7 +218,11 @@ pub const BINDING_CONTEXT_TAG: usize = 0x0DEC0DEC;
 pub const IC_DISABLED_VALUE: usize = 0xABBAABBA;
 pub const IC_ZAP_VALUE: usize = 0xABBADEEC;
 
-pub trait HValTrait: Sized + Copy {
+/// Implemented by the types that stand for heap objects. They are never
+/// constructed, references to them point at objects in the heap, and their
+/// methods write the object through `addr`. The `UnsafeCell` they wrap makes
+/// that allowed through a shared reference.
+pub trait HValTrait: Sized {
     fn addr(&self) -> *mut u8 {
         unsafe { std::mem::transmute(self) }
     }
@@ -162,12 +230,72 @@ pub trait HValTrait: Sized + Copy {
     const TAG: HeapTag;
 }
 
+/// Size of a field of a heap object. Fields hold full pointers, or 32 bit
+/// offsets from the heap base with the `compressed-pointers` feature.
+#[cfg(not(feature = "compressed-pointers"))]
+pub const FIELD_SIZE: usize = 8;
+#[cfg(feature = "compressed-pointers")]
+pub const FIELD_SIZE: usize = 4;
+
+/// Number of fields a raw 64 bit value (e.g. a code pointer) takes up.
+pub const RAW_FIELDS: isize = (8 / FIELD_SIZE) as isize;
+
+/// The object header is always a full word, only fields are compressed.
+const HEADER_SIZE: isize = 8;
+
 const fn interior_offset(x: isize) -> isize {
-    return x * std::mem::size_of::<isize>() as isize - 1;
+    return x * FIELD_SIZE as isize - 1 + ((x != 0) as isize) * (HEADER_SIZE - FIELD_SIZE as isize);
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HValue;
+/// Loads the value of the field at `slot` of a heap object.
+///
+/// # Safety
+///
+/// `slot` has to be a field of a live heap object.
+#[cfg(not(feature = "compressed-pointers"))]
+#[inline(always)]
+pub unsafe fn load_field(slot: *mut *mut u8) -> *mut u8 {
+    *slot
+}
+
+/// Stores `value` to the field at `slot` of a heap object.
+///
+/// # Safety
+///
+/// `slot` has to be a field of a live heap object. With compressed pointers
+/// `value` has to be compressible: an immediate, a small integer or a
+/// pointer into the heap cage.
+#[cfg(not(feature = "compressed-pointers"))]
+#[inline(always)]
+pub unsafe fn store_field(slot: *mut *mut u8, value: *mut u8) {
+    *slot = value;
+}
+
+/// Loads the value of the field at `slot` of a heap object.
+///
+/// # Safety
+///
+/// `slot` has to be a field of a live heap object.
+#[cfg(feature = "compressed-pointers")]
+#[inline(always)]
+pub unsafe fn load_field(slot: *mut *mut u8) -> *mut u8 {
+    crate::gc::compress::decompress(*(slot as *mut u32))
+}
+
+/// Stores `value` to the field at `slot` of a heap object.
+///
+/// # Safety
+///
+/// `slot` has to be a field of a live heap object. With compressed pointers
+/// `value` has to be compressible: an immediate, a small integer or a
+/// pointer into the heap cage.
+#[cfg(feature = "compressed-pointers")]
+#[inline(always)]
+pub unsafe fn store_field(slot: *mut *mut u8, value: *mut u8) {
+    *(slot as *mut u32) = crate::gc::compress::compress(value);
+}
+
+pub struct HValue(UnsafeCell<()>);
 
 impl HValTrait for HValue {
     const TAG: HeapTag = HeapTag::Nil;
@@ -179,6 +307,7 @@ impl HValue {
     pub const GC_FORWARD_OFF: isize = interior_offset(1);
     pub const REPR_OFF: isize = interior_offset(0) + 1;
     pub const GENERATION_OFF: isize = interior_offset(0) + 2;
+    pub const SITE_OFF: isize = interior_offset(0) + 3;
 
     pub fn is_soft_gc_marked(&self) -> bool {
         if Self::is_unboxed(self.addr()) {
@@ -214,9 +343,17 @@ impl HValue {
         }
     }
     #[inline]
+    #[cfg(not(feature = "nan-boxing"))]
     pub const fn is_unboxed(addr: *mut u8) -> bool {
         return unsafe { (addr as usize & 0x01) == 0 };
     }
+    /// With NaN-boxing every value with one of the upper 16 bits set is a
+    /// number, pointers and the other immediates keep them clear.
+    #[inline]
+    #[cfg(feature = "nan-boxing")]
+    pub const fn is_unboxed(addr: *mut u8) -> bool {
+        return unsafe { (addr as usize as u64 >> 48) != 0 };
+    }
     #[inline]
     pub const fn cast(addr: *mut u8) -> *mut HValue {
         return addr as *mut HValue;
@@ -231,7 +368,7 @@ impl HValue {
     }
 
     pub fn get_tag(addr: *mut u8) -> HeapTag {
-        if addr == (HeapTag::Nil as u8 as *mut u8) {
+        if addr == NIL {
             return HeapTag::Nil;
         }
 
@@ -239,6 +376,14 @@ impl HValue {
             return HeapTag::Number;
         }
 
+        if Self::is_immediate(addr) {
+            return if HBoolean::is_boolean(addr) {
+                HeapTag::Boolean
+            } else {
+                HeapTag::Undefined
+            };
+        }
+
         return unsafe { std::mem::transmute(*addr.offset(Self::TAG_OFFSET)) };
     }
 
@@ -246,8 +391,30 @@ impl HValue {
         return unsafe { std::mem::transmute(*(addr.offset(Self::REPR_OFF))) };
     }
 
+    /// Number of collections this object survived.
+    pub fn generation(&self) -> u8 {
+        unsafe { *self.addr().offset(Self::GENERATION_OFF) }
+    }
+
+    pub fn set_generation(&self, generation: u8) {
+        unsafe {
+            *self.addr().offset(Self::GENERATION_OFF) = generation;
+        }
+    }
+
+    /// Allocation site this object was allocated at, 0 if it is unknown.
+    pub fn site(&self) -> u32 {
+        unsafe { std::ptr::read_unaligned(self.addr().offset(Self::SITE_OFF) as *const u32) }
+    }
+
+    pub fn set_site(&self, site: u32) {
+        unsafe {
+            std::ptr::write_unaligned(self.addr().offset(Self::SITE_OFF) as *mut u32, site);
+        }
+    }
+
     pub fn get_gc_mark(&self) -> *mut u8 {
-        return unsafe { *(self.addr().offset(Self::GC_FORWARD_OFF) as *mut *mut u8) };
+        return unsafe { load_field(self.addr().offset(Self::GC_FORWARD_OFF) as *mut *mut u8) };
     }
 
     pub fn is_marked(&self) -> bool {
@@ -261,99 +428,151 @@ impl HValue {
     pub fn set_gc_mark(&self, new_addr: *mut u8) {
         unsafe {
             *(self.addr().offset(Self::GC_MARK_OFF)) |= 0x80;
-            *(self.addr().offset(Self::GC_FORWARD_OFF) as *mut *mut u8) = new_addr;
+            store_field(
+                self.addr().offset(Self::GC_FORWARD_OFF) as *mut *mut u8,
+                new_addr,
+            );
         }
     }
 
-    pub fn size(&self) -> usize {
-        const PTR_SIZE: usize = 8;
+    /// Returns true for nil, undefined, booleans and the hole.
+    #[inline]
+    pub fn is_immediate(addr: *mut u8) -> bool {
+        addr as usize & 0x01 != 0 && addr as usize <= MAX_IMMEDIATE
+    }
+
+    /// Returns true if `addr` points to an object in the heap, i.e. it is
+    /// neither an unboxed number nor an immediate. Heap pointers are always
+    /// odd, which keeps zeroed fields from being followed when NaN-boxing.
+    #[inline]
+    pub fn is_heap_pointer(addr: *mut u8) -> bool {
+        addr as usize & 0x01 != 0 && addr as usize > MAX_IMMEDIATE && !Self::is_unboxed(addr)
+    }
+
+    /// Calls `f` for every pointer slot of this object.
+    ///
+    /// Slots may hold unboxed values or nil, callers have to check the
+    /// loaded value with `HValue::is_heap_pointer` before following it.
+    pub fn visit_slots<F: FnMut(SlotName, *mut *mut u8)>(&self, mut f: F) {
         unsafe {
-            let mut size = PTR_SIZE;
             match self.tag() {
                 HeapTag::Context => {
-                    size += (2 * (*self.as_::<HContext>()).slots() as usize) * PTR_SIZE;
+                    let ctx: &HContext = &*self.as_::<HContext>();
+                    if ctx.has_parent() {
+                        f(SlotName::Parent, ctx.parent_slot());
+                    }
+
+                    for i in 0..ctx.slots() {
+                        f(SlotName::Context(i), ctx.get_slot_address(i));
+                    }
                 }
                 HeapTag::Function => {
-                    size += 4 * PTR_SIZE;
+                    let fun: &HFunction = &*self.as_::<HFunction>();
+                    if fun.parent() != BINDING_CONTEXT_TAG as *mut u8 {
+                        f(SlotName::Parent, fun.parent_slot());
+                    }
+                    f(SlotName::Root, fun.root_slot());
                 }
-                HeapTag::Number => {
-                    size += 8;
+                HeapTag::Object | HeapTag::Array => {
+                    // Array is object, so we can use object accessors for it
+                    let obj: &HObject = &*(self.addr() as *mut HObject);
+                    f(SlotName::Proto, obj.proto_slot());
+                    f(SlotName::Map, obj.map_slot());
+                    for i in 0..HObject::in_object_slots(self.addr()) {
+                        f(SlotName::Slot(i), HObject::slot_address_s(self.addr(), i));
+                    }
+                    if self.tag() == HeapTag::Array {
+                        f(SlotName::Elements, HArray::elements_slot(self.addr()));
+                    }
                 }
-
-                HeapTag::Boolean => {
-                    size += 8;
+                HeapTag::Elements => {
+                    let elements: &HElements = &*self.as_::<HElements>();
+                    for i in 0..elements.capacity() {
+                        f(SlotName::Slot(i), elements.slot_address(i));
+                    }
+                }
+                HeapTag::Shape => {
+                    let shape: &HShape = &*self.as_::<HShape>();
+                    f(SlotName::Parent, shape.parent_slot());
+                    f(SlotName::Key, shape.key_slot());
+                    f(SlotName::Transitions, shape.transitions_slot());
                 }
-
                 HeapTag::String => {
-                    size += 2 * PTR_SIZE;
-                    match Self::get_repr(self.addr()) {
-                        0 => {
-                            size += (*self.as_::<HString>()).length() as usize;
-                        }
-                        _ => {
-                            size += 2 * PTR_SIZE;
+                    let string: &HString = &*self.as_::<HString>();
+                    match string.repr() {
+                        StrRepr::Cons => {
+                            f(SlotName::Left, string.left_slot());
+                            f(SlotName::Right, string.right_slot());
                         }
+                        StrRepr::Sliced => f(SlotName::Parent, string.parent_slot()),
+                        StrRepr::Normal => (),
                     }
                 }
-                HeapTag::Object => {
-                    size += 3 * PTR_SIZE;
-                }
-                HeapTag::Array => {
-                    size += 4 * PTR_SIZE;
-                }
                 HeapTag::Map => {
-                    size += (1 + ((*self.as_::<HMap>()).size() as usize) << 1) * PTR_SIZE;
+                    let map: &HMap = &*self.as_::<HMap>();
+                    for i in 0..map.size() {
+                        f(SlotName::MapKey(i), map.get_slot_address(i << 1));
+                        f(SlotName::MapValue(i), map.get_slot_address((i << 1) + 1));
+                    }
                 }
-
                 _ => (),
             }
-
-            size
         }
     }
 
-    pub fn copy_to(&self, addr: &mut crate::gc::Address) -> (*mut u8, usize) {
+    pub fn size(&self) -> usize {
         const PTR_SIZE: usize = 8;
         unsafe {
-            let mut size = PTR_SIZE;
+            let mut size = HEADER_SIZE as usize;
             match self.tag() {
                 HeapTag::Context => {
-                    size += (2 * (*self.as_::<HContext>()).slots() as usize) * PTR_SIZE;
+                    size += (2 + (*self.as_::<HContext>()).slots() as usize) * FIELD_SIZE;
                 }
                 HeapTag::Function => {
-                    size += 4 * PTR_SIZE;
+                    size += (3 + RAW_FIELDS as usize) * FIELD_SIZE;
                 }
                 HeapTag::Number => {
                     size += 8;
                 }
 
-                HeapTag::Boolean => {
-                    size += 8;
-                }
-
                 HeapTag::String => {
-                    size += 2 * PTR_SIZE;
-                    match Self::get_repr(self.addr()) {
-                        0 => {
-                            size += (*self.as_::<HString>()).length() as usize;
-                        }
-                        _ => {
-                            size += 2 * PTR_SIZE;
-                        }
-                    }
+                    let string: &HString = &*self.as_::<HString>();
+                    size += match string.repr() {
+                        StrRepr::Normal => HString::flat_size(string.length()),
+                        StrRepr::Cons => HString::CONS_SIZE,
+                        StrRepr::Sliced => HString::SLICED_SIZE,
+                    };
                 }
                 HeapTag::Object => {
-                    size += 3 * PTR_SIZE;
+                    size += (3 + HObject::in_object_slots(self.addr()) as usize) * FIELD_SIZE;
+                }
+                HeapTag::Shape => {
+                    size += HShape::SIZE;
                 }
                 HeapTag::Array => {
-                    size += 4 * PTR_SIZE;
+                    size += 5 * FIELD_SIZE;
+                }
+                HeapTag::Elements => {
+                    size += HElements::elements_size((*self.as_::<HElements>()).capacity());
                 }
                 HeapTag::Map => {
-                    size += (1 + ((*self.as_::<HMap>()).size() as usize) << 1) * PTR_SIZE;
+                    size += HMap::map_size((*self.as_::<HMap>()).size());
+                }
+                HeapTag::BigInt => {
+                    size += HBigInt::digits_size(HBigInt::length(self.addr()));
                 }
 
-                _ => unimplemented!(),
+                _ => (),
             }
+
+            // keep the next object's header aligned
+            crate::mem::align_usize(size, PTR_SIZE)
+        }
+    }
+
+    pub fn copy_to(&self, addr: &mut crate::gc::Address) -> (*mut u8, usize) {
+        unsafe {
+            let size = self.size();
             let result = self.addr().offset(interior_offset(0));
             std::ptr::copy_nonoverlapping(
                 result,
@@ -364,25 +583,115 @@ impl HValue {
             return (result, size);
         }
     }
+
+    /// Allocates a copy of `live[0]` with the same tag, representation bits
+    /// and fields, the values it references are shared. The other values in
+    /// `live` are kept alive.
+    pub fn shallow_copy(gc: &mut CopyGC, live: &mut [*mut u8]) -> *mut u8 {
+        let (tag, size) = unsafe {
+            let value = &*HValue::cast(live[0]);
+            (value.tag(), value.size() - HEADER_SIZE as usize)
+        };
+        let copy = gc.alloc_tagged_keep(tag, size, live).to_mut_ptr::<u8>();
+        unsafe {
+            *copy.offset(Self::REPR_OFF) = Self::get_repr(live[0]);
+            std::ptr::copy_nonoverlapping(
+                live[0].offset(interior_offset(1)),
+                copy.offset(interior_offset(1)),
+                size,
+            );
+        }
+        copy
+    }
 }
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HContext;
+pub struct HContext(UnsafeCell<()>);
 
 impl HValTrait for HContext {
     const TAG: HeapTag = HeapTag::Context;
 }
 
+/// Closure scope: a parent context and a fixed number of variable slots.
+///
+/// Binding contexts are created without a parent, their parent slot holds
+/// `BINDING_CONTEXT_TAG` until `bind` links them into a scope chain.
 impl HContext {
+    /// Allocates a context with `slots` nil slots. `parent` is nil for the
+    /// outermost context.
+    pub fn new(gc: &mut CopyGC, parent: *mut u8, slots: u32) -> *mut u8 {
+        let mut live = [parent];
+        let ctx = gc
+            .alloc_tagged_keep(
+                HeapTag::Context,
+                (2 + slots as usize) * FIELD_SIZE,
+                &mut live,
+            )
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *(ctx.offset(Self::SLOTS_OFFSET) as *mut u32) = slots;
+            let context: &HContext = &*(ctx as *mut HContext);
+            context.set_parent(live[0]);
+            for i in 0..slots {
+                context.set_slot(i, NIL);
+            }
+        }
+        ctx
+    }
+
+    /// Allocates a binding context, see `bind`.
+    pub fn new_binding(gc: &mut CopyGC, slots: u32) -> *mut u8 {
+        Self::new(gc, BINDING_CONTEXT_TAG as *mut u8, slots)
+    }
+
+    pub fn is_binding(&self) -> bool {
+        self.parent() == BINDING_CONTEXT_TAG as *mut u8
+    }
+
+    /// Links a binding context to its parent.
+    pub fn bind(&self, parent: *mut u8) {
+        assert!(self.is_binding(), "context is already bound");
+        self.set_parent(parent);
+    }
+
+    /// Context `depth` links up the scope chain, `self` for depth 0.
+    pub fn ancestor(&self, depth: u32) -> &HContext {
+        let mut ctx = self;
+        for _ in 0..depth {
+            assert!(ctx.has_parent(), "scope chain is shorter than {}", depth);
+            ctx = unsafe { &*(ctx.parent() as *mut HContext) };
+        }
+        ctx
+    }
+
+    /// Slot `index` of the context `depth` links up, like `LoadCtx`.
+    pub fn lookup(&self, depth: u32, index: u32) -> *mut u8 {
+        let ctx = self.ancestor(depth);
+        assert!(index < ctx.slots());
+        unsafe { load_field(ctx.get_slot_address(index)) }
+    }
+
+    /// Stores into slot `index` of the context `depth` links up, like
+    /// `StoreCtx`.
+    pub fn store(&self, depth: u32, index: u32, value: *mut u8) {
+        let ctx = self.ancestor(depth);
+        assert!(index < ctx.slots());
+        ctx.set_slot(index, value);
+    }
+
     pub fn parent_slot(&self) -> *mut *mut u8 {
         return unsafe { (self.addr().offset(Self::PARENT_OFFSET)) as *mut *mut _ };
     }
 
     pub fn parent(&self) -> *mut u8 {
-        unsafe { *self.parent_slot() }
+        unsafe { load_field(self.parent_slot()) }
+    }
+
+    pub fn set_parent(&self, parent: *mut u8) {
+        unsafe { store_field(self.parent_slot(), parent) }
     }
 
+    /// False for the outermost context and unbound binding contexts.
     pub fn has_parent(&self) -> bool {
-        !self.parent().is_null()
+        HValue::is_heap_pointer(self.parent())
     }
 
     pub fn slots(&self) -> u32 {
@@ -390,11 +699,15 @@ impl HContext {
     }
 
     pub fn get_slot(&self, idx: u32) -> *mut HValue {
-        unsafe { HValue::cast(*self.get_slot_address(idx)) }
+        unsafe { HValue::cast(load_field(self.get_slot_address(idx))) }
+    }
+
+    pub fn set_slot(&self, idx: u32, value: *mut u8) {
+        unsafe { store_field(self.get_slot_address(idx), value) }
     }
 
     pub fn has_slot(&self, idx: u32) -> bool {
-        return unsafe { *self.get_slot_address(idx) != HeapTag::Nil as u8 as *mut u8 };
+        return unsafe { load_field(self.get_slot_address(idx)) != HeapTag::Nil as u8 as *mut u8 };
     }
 
     pub fn get_slot_address(&self, idx: u32) -> *mut *mut u8 {
@@ -412,12 +725,15 @@ impl HContext {
 #[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
 #[repr(u8)]
 pub enum StrRepr {
+    /// UTF-8 bytes stored in the string itself.
     Normal = 0x00,
+    /// Concatenation of two strings.
     Cons = 0x01,
+    /// Part of a flat string, referenced by parent and byte offset.
+    Sliced = 0x02,
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HString;
+pub struct HString(UnsafeCell<()>);
 
 impl HValTrait for HString {
     const TAG: HeapTag = HeapTag::String;
@@ -426,11 +742,29 @@ impl HValTrait for HString {
 impl HString {
     pub const HASH_OFFSET: isize = interior_offset(1);
     pub const LENGTH_OFFSET: isize = interior_offset(2);
-    pub const VALUE_OFFSET: isize = interior_offset(3);
-    pub const LEFT_CONS_OFFSET: isize = interior_offset(3);
-    pub const RIGHT_CONS_OFFSET: isize = interior_offset(4);
+    pub const CHAR_LENGTH_OFFSET: isize = interior_offset(3);
+    pub const VALUE_OFFSET: isize = interior_offset(4);
+    pub const LEFT_CONS_OFFSET: isize = interior_offset(4);
+    pub const RIGHT_CONS_OFFSET: isize = interior_offset(5);
+    pub const CONS_DEPTH_OFFSET: isize = interior_offset(6);
+    pub const PARENT_OFFSET: isize = interior_offset(4);
+    pub const SLICE_OFFSET: isize = interior_offset(5);
+    /// Payload sizes of cons and sliced strings.
+    pub const CONS_SIZE: usize = 6 * FIELD_SIZE;
+    pub const SLICED_SIZE: usize = 5 * FIELD_SIZE;
     pub const MIN_CONS_LEN: usize = 24;
-
+    /// Shorter substrings are copied instead of sliced.
+    pub const MIN_SLICE_LEN: usize = 13;
+    /// The repr byte holds the `StrRepr` in its low bits and flags above.
+    pub const REPR_MASK: u8 = 0x0f;
+    pub const INTERNED_BIT: u8 = 0x80;
+    /// Cons strings deeper than this are flattened by the collector.
+    pub const FLATTEN_CONS_DEPTH: u32 = 32;
+    /// Longest cons string the collector flattens, flattening a rope with
+    /// shared parts may need much more memory than the rope itself.
+    pub const MAX_FLATTEN_LENGTH: u32 = 64 * 1024;
+
+    /// Length in bytes of the UTF-8 contents.
     pub fn static_length(addr: *mut u8) -> u32 {
         return unsafe { *(addr.offset(HString::LENGTH_OFFSET) as *mut u32) };
     }
@@ -438,173 +772,3291 @@ impl HString {
     pub fn length(&self) -> u32 {
         Self::static_length(self.addr())
     }
-}
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HMap;
 
-impl HValTrait for HMap {
-    const TAG: HeapTag = HeapTag::Map;
-}
+    /// Length in code points, cached when the string is created.
+    pub fn static_char_length(addr: *mut u8) -> u32 {
+        unsafe { *(addr.offset(Self::CHAR_LENGTH_OFFSET) as *mut u32) }
+    }
 
-impl HMap {
-    pub fn size(&self) -> u32 {
-        return unsafe { *(self.addr().offset(Self::SIZE_OFFSET) as *mut u32) };
+    pub fn char_length(&self) -> u32 {
+        Self::static_char_length(self.addr())
     }
 
-    pub fn get_slot_address(&self, index: u32) -> *mut *mut u8 {
-        return unsafe {
-            self.space()
-                .offset(index as isize * crate::mem::ptr_width() as isize)
-                as *mut *mut _
-        };
+    fn set_lengths(addr: *mut u8, length: u32, char_length: u32) {
+        unsafe {
+            *(addr.offset(Self::LENGTH_OFFSET) as *mut u32) = length;
+            *(addr.offset(Self::CHAR_LENGTH_OFFSET) as *mut u32) = char_length;
+        }
     }
 
-    pub fn get_slot(&self, index: u32) -> *mut HValue {
-        return unsafe { HValue::cast(*self.get_slot_address(index)) };
+    /// Whether all code points are ASCII, so bytes and code points match.
+    pub fn is_ascii(&self) -> bool {
+        self.length() == self.char_length()
     }
 
-    pub fn has_slot(&self, index: u32) -> bool {
-        unsafe { *self.get_slot_address(index) != HeapTag::Nil as u8 as *mut u8 }
+    pub fn static_repr(addr: *mut u8) -> StrRepr {
+        match HValue::get_repr(addr) & Self::REPR_MASK {
+            0 => StrRepr::Normal,
+            1 => StrRepr::Cons,
+            _ => StrRepr::Sliced,
+        }
     }
 
-    pub fn space(&self) -> *mut u8 {
-        unsafe { self.addr().offset(Self::SPACE_OFFSET) }
+    pub fn repr(&self) -> StrRepr {
+        Self::static_repr(self.addr())
     }
-    pub const SPACE_OFFSET: isize = interior_offset(2);
-    pub const SIZE_OFFSET: isize = interior_offset(1);
-}
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HArray;
 
-impl HValTrait for HArray {
-    const TAG: HeapTag = HeapTag::Array;
-}
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HNumber;
+    pub fn is_cons(addr: *mut u8) -> bool {
+        HValue::get_repr(addr) & Self::REPR_MASK == StrRepr::Cons as u8
+    }
 
-impl HNumber {
-    pub const fn tag(value: i64) -> i64 {
-        return value << 1;
+    pub fn is_sliced(addr: *mut u8) -> bool {
+        HValue::get_repr(addr) & Self::REPR_MASK == StrRepr::Sliced as u8
     }
-}
 
-impl HArray {
-    pub fn length(obj: *mut u8, shrink: bool) -> usize {
+    /// Interned strings are the one copy of their contents in the intern
+    /// table, two interned strings are equal only if they are the same
+    /// object.
+    pub fn is_interned(addr: *mut u8) -> bool {
+        HValue::get_repr(addr) & Self::INTERNED_BIT != 0
+    }
+
+    pub fn set_interned(addr: *mut u8) {
+        debug_assert!(!Self::is_cons(addr));
         unsafe {
-            let mut result = *(obj.offset(Self::LENGTH_OFFSET) as *mut usize);
-            if shrink {
-                let mut shrinked = result;
-                let shrinked_ptr: *mut u8;
-                let slot: *mut *mut u8;
+            *addr.offset(HValue::REPR_OFF) |= Self::INTERNED_BIT;
+        }
+    }
 
-                if shrinked < 0 {
-                } else {
-                    shrinked -= 1;
-                    shrinked_ptr = HNumber::tag(shrinked as i64) as *mut u8;
-                }
+    /// Payload size of a flat string of `length` bytes.
+    pub fn flat_size(length: u32) -> usize {
+        3 * FIELD_SIZE + crate::mem::align_usize(length as usize, crate::mem::ptr_width_usize())
+    }
 
-                if result != (shrinked + 1) {
-                    result = shrinked + 1;
-                    HArray::set_length(obj, result);
-                }
-            }
+    pub fn left_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::LEFT_CONS_OFFSET) as *mut *mut u8 }
+    }
 
-            result
-        }
+    pub fn right_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::RIGHT_CONS_OFFSET) as *mut *mut u8 }
     }
 
-    pub fn set_length(obj: *mut u8, len: usize) {
-        unsafe {
-            *(obj.offset(Self::LENGTH_OFFSET) as *mut usize) = len;
+    pub fn left(&self) -> *mut u8 {
+        unsafe { load_field(self.left_slot()) }
+    }
+
+    pub fn right(&self) -> *mut u8 {
+        unsafe { load_field(self.right_slot()) }
+    }
+
+    /// Depth of the cons string tree, 0 for flat strings.
+    pub fn depth(&self) -> u32 {
+        if !Self::is_cons(self.addr()) {
+            return 0;
         }
+        unsafe { *(self.addr().offset(Self::CONS_DEPTH_OFFSET) as *mut u32) }
     }
 
-    pub const VAR_ARG_LEN: usize = 16;
-    pub const DENSE_LENGTH_MAX: usize = 128;
-    pub const LENGTH_OFFSET: isize = interior_offset(4);
-}
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HObject;
+    /// Flat string a sliced string points into.
+    pub fn parent_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::PARENT_OFFSET) as *mut *mut u8 }
+    }
 
-impl HValTrait for HObject {
-    const TAG: HeapTag = HeapTag::Object;
-}
+    pub fn parent(&self) -> *mut u8 {
+        unsafe { load_field(self.parent_slot()) }
+    }
 
-impl HObject {
-    pub fn map_slot_s(addr: *mut u8) -> *mut *mut u8 {
-        return unsafe { addr.offset(Self::MAP_OFFSET) as *mut *mut _ };
+    /// Byte offset of a sliced string in its parent.
+    pub fn slice_offset(&self) -> u32 {
+        unsafe { *(self.addr().offset(Self::SLICE_OFFSET) as *mut u32) }
     }
 
-    pub fn map_s(addr: *mut u8) -> *mut u8 {
-        return unsafe { *Self::map_slot_s(addr) };
+    /// Allocates a flat string of `length` bytes, filled with zeroes.
+    pub fn new_flat(gc: &mut CopyGC, length: u32) -> *mut u8 {
+        let addr = gc
+            .alloc_tagged(HeapTag::String, Self::flat_size(length))
+            .to_mut_ptr::<u8>();
+        Self::set_lengths(addr, length, length);
+        addr
     }
 
-    pub fn map(&self) -> *mut u8 {
-        Self::map_s(self.addr())
+    /// Allocates a flat string with contents `bytes`, which have to be
+    /// valid UTF-8.
+    pub fn from_utf8(gc: &mut CopyGC, bytes: &[u8]) -> Result<*mut u8, std::str::Utf8Error> {
+        Ok(Self::from_str(gc, std::str::from_utf8(bytes)?))
     }
 
-    pub fn map_slot(&self) -> *mut *mut u8 {
-        Self::map_slot_s(self.addr())
+    pub fn from_str(gc: &mut CopyGC, value: &str) -> *mut u8 {
+        let addr = Self::new_flat(gc, value.len() as u32);
+        Self::set_lengths(addr, value.len() as u32, value.chars().count() as u32);
+        unsafe {
+            std::ptr::copy_nonoverlapping(value.as_ptr(), Self::value_s(addr), value.len());
+        }
+        addr
     }
 
-    pub fn proto_slot_s(addr: *mut u8) -> *mut *mut u8 {
-        return unsafe { addr.offset(Self::PROTO_OFFSET) as *mut *mut _ };
+    /// Characters of a flat string.
+    pub fn value_s(addr: *mut u8) -> *mut u8 {
+        debug_assert!(Self::static_repr(addr) == StrRepr::Normal);
+        unsafe { addr.offset(Self::VALUE_OFFSET) }
     }
 
-    pub fn proto_s(addr: *mut u8) -> *mut u8 {
-        unsafe { *Self::proto_slot_s(addr) }
+    pub fn value(&self) -> *mut u8 {
+        Self::value_s(self.addr())
     }
 
-    pub fn proto(&self) -> *mut u8 {
-        Self::proto_s(self.addr())
+    /// Characters of a flat or sliced string.
+    pub fn chars_s(addr: *mut u8) -> *mut u8 {
+        if Self::is_sliced(addr) {
+            let sliced: &HString = unsafe { &*(addr as *mut HString) };
+            return unsafe {
+                Self::value_s(sliced.parent()).offset(sliced.slice_offset() as isize)
+            };
+        }
+        Self::value_s(addr)
     }
 
-    pub fn proto_slot(&self) -> *mut *mut u8 {
-        Self::proto_slot_s(self.addr())
+    /// Contents of the string, cons strings are copied into a buffer.
+    pub fn bytes(&self) -> Cow<'_, [u8]> {
+        let length = self.length() as usize;
+        if Self::is_cons(self.addr()) {
+            let mut buf = vec![0u8; length];
+            self.write_chars(buf.as_mut_ptr());
+            Cow::Owned(buf)
+        } else {
+            Cow::Borrowed(unsafe { std::slice::from_raw_parts(Self::chars_s(self.addr()), length) })
+        }
     }
 
-    pub const MASK_OFFSET: isize = interior_offset(1);
-    pub const MAP_OFFSET: isize = interior_offset(2);
-    pub const PROTO_OFFSET: isize = interior_offset(3);
-}
-#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
-pub struct HFunction;
+    pub fn to_rust_string(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into_owned()
+    }
 
-impl HValTrait for HFunction {
-    const TAG: HeapTag = HeapTag::Function;
-}
+    /// Code point at `index`, ASCII strings are indexed directly while
+    /// others are decoded from the start.
+    pub fn char_at(&self, index: u32) -> Option<char> {
+        if index >= self.char_length() {
+            return None;
+        }
+        let bytes = self.bytes();
+        if self.is_ascii() {
+            return Some(bytes[index as usize] as char);
+        }
+        unsafe { std::str::from_utf8_unchecked(&bytes) }
+            .chars()
+            .nth(index as usize)
+    }
 
-impl HFunction {
-    pub const PARENT_OFFSET: isize = interior_offset(1);
-    pub const CODE_OFFSET: isize = interior_offset(2);
-    pub const ROOT_OFFSET: isize = interior_offset(3);
-    pub const ARGC_OFFSET: isize = interior_offset(4);
+    /// Byte offset of the code point at `index` in `bytes`.
+    fn byte_offset(bytes: &[u8], ascii: bool, index: u32) -> u32 {
+        if ascii {
+            return index;
+        }
+        unsafe { std::str::from_utf8_unchecked(bytes) }
+            .char_indices()
+            .nth(index as usize)
+            .map(|(offset, _)| offset as u32)
+            .unwrap_or(bytes.len() as u32)
+    }
 
-    pub fn root_s(addr: *mut u8) -> *mut u8 {
-        unsafe { *(addr.offset(Self::ROOT_OFFSET) as *mut *mut u8) }
+    /// Code points `start..end` of `addr`, both clamped to the length.
+    /// Short results are copied, longer ones become a sliced string that
+    /// shares the characters of its flat parent.
+    pub fn substring(gc: &mut CopyGC, addr: *mut u8, start: u32, end: u32) -> *mut u8 {
+        let string: &HString = unsafe { &*(addr as *mut HString) };
+        let end = std::cmp::min(end, string.char_length());
+        let start = std::cmp::min(start, end);
+        if start == 0 && end == string.char_length() {
+            return addr;
+        }
+        let (byte_start, byte_end) = {
+            let bytes = string.bytes();
+            let ascii = string.is_ascii();
+            (
+                Self::byte_offset(&bytes, ascii, start),
+                Self::byte_offset(&bytes, ascii, end),
+            )
+        };
+        let length = byte_end - byte_start;
+
+        let mut parent = Self::flatten(gc, addr);
+        let mut offset = byte_start;
+        if Self::is_sliced(parent) {
+            let sliced: &HString = unsafe { &*(parent as *mut HString) };
+            offset += sliced.slice_offset();
+            parent = sliced.parent();
+        }
+
+        let mut live = [parent];
+        unsafe {
+            if (length as usize) < Self::MIN_SLICE_LEN {
+                let result = Self::new_flat_keep(gc, length, &mut live);
+                *(result.offset(Self::CHAR_LENGTH_OFFSET) as *mut u32) = end - start;
+                std::ptr::copy_nonoverlapping(
+                    Self::value_s(live[0]).offset(offset as isize),
+                    Self::value_s(result),
+                    length as usize,
+                );
+                return result;
+            }
+
+            let result = gc
+                .alloc_tagged_keep(HeapTag::String, Self::SLICED_SIZE, &mut live)
+                .to_mut_ptr::<u8>();
+            *result.offset(HValue::REPR_OFF) = StrRepr::Sliced as u8;
+            Self::set_lengths(result, length, end - start);
+            *(result.offset(Self::SLICE_OFFSET) as *mut u32) = offset;
+            store_field((*(result as *mut HString)).parent_slot(), live[0]);
+            result
+        }
     }
 
-    pub fn root_slot(&self) -> *mut *mut u8 {
-        unsafe { self.addr().offset(Self::ROOT_OFFSET) as *mut *mut u8 }
+    fn new_flat_keep(gc: &mut CopyGC, length: u32, live: &mut [*mut u8]) -> *mut u8 {
+        let addr = gc
+            .alloc_tagged_keep(HeapTag::String, Self::flat_size(length), live)
+            .to_mut_ptr::<u8>();
+        Self::set_lengths(addr, length, length);
+        addr
     }
 
-    pub fn root(&self) -> *mut u8 {
-        unsafe { *(self.root_slot()) }
+    /// Upper case version of `addr` following the Unicode case mappings,
+    /// which may change the length (e.g. "ß" becomes "SS").
+    pub fn to_upper_case(gc: &mut CopyGC, addr: *mut u8) -> *mut u8 {
+        let value = unsafe { (*(addr as *mut HString)).to_rust_string() };
+        let upper = value.to_uppercase();
+        if upper == value {
+            return addr;
+        }
+        Self::from_str(gc, &upper)
     }
 
-    pub fn argc(&self) -> u32 {
-        unsafe { *self.argc_off() }
+    /// Lower case version of `addr` following the Unicode case mappings.
+    pub fn to_lower_case(gc: &mut CopyGC, addr: *mut u8) -> *mut u8 {
+        let value = unsafe { (*(addr as *mut HString)).to_rust_string() };
+        let lower = value.to_lowercase();
+        if lower == value {
+            return addr;
+        }
+        Self::from_str(gc, &lower)
     }
 
-    pub fn parent(&self) -> *mut u8 {
-        unsafe { *self.parent_slot() }
+    /// Hash of the string contents. It is computed on first use and cached
+    /// in the hash field, where 0 means "not computed yet".
+    pub fn hash(&self) -> u32 {
+        unsafe {
+            let slot = self.addr().offset(Self::HASH_OFFSET) as *mut u32;
+            if *slot == 0 {
+                *slot = Self::hash_bytes(&self.bytes());
+            }
+            *slot
+        }
     }
 
-    pub fn parent_slot(&self) -> *mut *mut u8 {
-        unsafe { self.addr().offset(Self::PARENT_OFFSET) as *mut *mut _ }
+    /// Hash a string with contents `bytes` has, never 0.
+    pub fn hash_bytes(bytes: &[u8]) -> u32 {
+        std::cmp::max(compute_hash(bytes), 1)
     }
 
-    pub fn argc_off(&self) -> *mut u32 {
-        unsafe { self.addr().offset(Self::ARGC_OFFSET) as *mut u32 }
+    fn cached_hash(addr: *mut u8) -> u32 {
+        unsafe { *(addr.offset(Self::HASH_OFFSET) as *mut u32) }
+    }
+
+    /// Concatenates two strings. Results shorter than `MIN_CONS_LEN` are
+    /// copied into a flat string, longer ones become a cons string
+    /// referencing both parts.
+    pub fn concat(gc: &mut CopyGC, left: *mut u8, right: *mut u8) -> *mut u8 {
+        let left_length = Self::static_length(left);
+        let right_length = Self::static_length(right);
+        if left_length == 0 {
+            return right;
+        }
+        if right_length == 0 {
+            return left;
+        }
+
+        let length = left_length + right_length;
+        let char_length = Self::static_char_length(left) + Self::static_char_length(right);
+        let mut live = [left, right];
+        unsafe {
+            if (length as usize) < Self::MIN_CONS_LEN {
+                let addr = Self::new_flat_keep(gc, length, &mut live);
+                Self::set_lengths(addr, length, char_length);
+                let value = Self::value_s(addr);
+                (*(live[0] as *mut HString)).write_chars(value);
+                (*(live[1] as *mut HString)).write_chars(value.offset(left_length as isize));
+                return addr;
+            }
+
+            let addr = gc
+                .alloc_tagged_keep(HeapTag::String, Self::CONS_SIZE, &mut live)
+                .to_mut_ptr::<u8>();
+            let depth = std::cmp::max(
+                (*(live[0] as *mut HString)).depth(),
+                (*(live[1] as *mut HString)).depth(),
+            ) + 1;
+            *addr.offset(HValue::REPR_OFF) = StrRepr::Cons as u8;
+            Self::set_lengths(addr, length, char_length);
+            *(addr.offset(Self::CONS_DEPTH_OFFSET) as *mut u32) = depth;
+            let string: &HString = &*(addr as *mut HString);
+            store_field(string.left_slot(), live[0]);
+            store_field(string.right_slot(), live[1]);
+            addr
+        }
+    }
+
+    /// Returns a flat string with the contents of `addr`. The cons string
+    /// is rewritten to reference the flat copy and an empty string, so it
+    /// is not flattened twice and the collector short-circuits it.
+    pub fn flatten(gc: &mut CopyGC, addr: *mut u8) -> *mut u8 {
+        if !Self::is_cons(addr) {
+            return addr;
+        }
+        unsafe {
+            let string: &HString = &*(addr as *mut HString);
+            let left = string.left();
+            if Self::static_length(string.right()) == 0 && !Self::is_cons(left) {
+                return left;
+            }
+
+            let length = string.length();
+            let mut live = [addr, std::ptr::null_mut()];
+            let flat = Self::new_flat_keep(gc, length, &mut live[..1]);
+            if !Self::is_cons(live[0]) {
+                // the collector flattened it in the meantime
+                return live[0];
+            }
+            let cons: &HString = &*(live[0] as *mut HString);
+            Self::set_lengths(flat, length, cons.char_length());
+            *(flat.offset(Self::HASH_OFFSET) as *mut u32) = Self::cached_hash(live[0]);
+            cons.write_chars(Self::value_s(flat));
+
+            live[1] = flat;
+            let empty = Self::new_flat_keep(gc, 0, &mut live);
+            if !Self::is_cons(live[0]) {
+                return live[1];
+            }
+            let cons: &HString = &*(live[0] as *mut HString);
+            store_field(cons.left_slot(), live[1]);
+            store_field(cons.right_slot(), empty);
+            *(live[0].offset(Self::CONS_DEPTH_OFFSET) as *mut u32) = 1;
+            live[1]
+        }
+    }
+
+    pub fn equals(a: *mut u8, b: *mut u8) -> bool {
+        if a == b {
+            return true;
+        }
+        if Self::is_interned(a) && Self::is_interned(b) {
+            return false;
+        }
+        if Self::static_length(a) != Self::static_length(b) {
+            return false;
+        }
+        let (hash_a, hash_b) = (Self::cached_hash(a), Self::cached_hash(b));
+        if hash_a != 0 && hash_b != 0 && hash_a != hash_b {
+            return false;
+        }
+        unsafe { (*(a as *mut HString)).bytes() == (*(b as *mut HString)).bytes() }
+    }
+
+    /// Orders strings by their bytes.
+    pub fn compare(a: *mut u8, b: *mut u8) -> Ordering {
+        if a == b {
+            return Ordering::Equal;
+        }
+        unsafe {
+            (*(a as *mut HString))
+                .bytes()
+                .cmp(&(*(b as *mut HString)).bytes())
+        }
+    }
+
+    /// Copies the characters of this string to `dst`, which has to have
+    /// room for `length` bytes. Cons strings are flattened on the way.
+    pub fn write_chars(&self, dst: *mut u8) {
+        let mut offset = 0;
+        let mut stack = vec![self.addr()];
+        while let Some(addr) = stack.pop() {
+            unsafe {
+                if Self::is_cons(addr) {
+                    let string: &HString = &*(addr as *mut HString);
+                    stack.push(string.right());
+                    stack.push(string.left());
+                    continue;
+                }
+                let length = Self::static_length(addr) as usize;
+                std::ptr::copy_nonoverlapping(
+                    Self::chars_s(addr),
+                    dst.offset(offset as isize),
+                    length,
+                );
+                offset += length;
+            }
+        }
+    }
+}
+pub struct HMap(UnsafeCell<()>);
+
+impl HValTrait for HMap {
+    const TAG: HeapTag = HeapTag::Map;
+}
+
+impl HMap {
+    /// Capacity of the table, always a power of two.
+    pub fn size(&self) -> u32 {
+        return unsafe { *(self.addr().offset(Self::SIZE_OFFSET) as *mut u32) };
+    }
+
+    /// Number of live entries.
+    pub fn count(&self) -> u32 {
+        unsafe { *(self.addr().offset(Self::COUNT_OFFSET) as *mut u32) }
+    }
+
+    /// Number of tombstones left behind by `delete`.
+    pub fn deleted(&self) -> u32 {
+        unsafe { *(self.addr().offset(Self::DELETED_OFFSET) as *mut u32) }
+    }
+
+    fn set_counts(&self, count: u32, deleted: u32) {
+        unsafe {
+            *(self.addr().offset(Self::COUNT_OFFSET) as *mut u32) = count;
+            *(self.addr().offset(Self::DELETED_OFFSET) as *mut u32) = deleted;
+        }
+    }
+
+    pub fn get_slot_address(&self, index: u32) -> *mut *mut u8 {
+        return unsafe { self.space().offset(index as isize * FIELD_SIZE as isize) as *mut *mut _ };
+    }
+
+    pub fn get_slot(&self, index: u32) -> *mut HValue {
+        return unsafe { HValue::cast(load_field(self.get_slot_address(index))) };
+    }
+
+    pub fn set_slot(&self, index: u32, value: *mut u8) {
+        unsafe { store_field(self.get_slot_address(index), value) }
+    }
+
+    pub fn has_slot(&self, index: u32) -> bool {
+        unsafe { load_field(self.get_slot_address(index)) != HeapTag::Nil as u8 as *mut u8 }
+    }
+
+    pub fn space(&self) -> *mut u8 {
+        unsafe { self.addr().offset(Self::SPACE_OFFSET) }
+    }
+
+    /// Payload size of a map with room for `size` entries.
+    pub fn map_size(size: u32) -> usize {
+        (3 + 2 * size as usize) * FIELD_SIZE
+    }
+
+    /// Allocates an empty map with room for at least `size` entries.
+    pub fn new(gc: &mut CopyGC, size: u32) -> *mut u8 {
+        Self::new_keep(gc, size, &mut [])
+    }
+
+    fn new_keep(gc: &mut CopyGC, size: u32, live: &mut [*mut u8]) -> *mut u8 {
+        let size = std::cmp::max(size, Self::MIN_SIZE).next_power_of_two();
+        let addr = gc
+            .alloc_tagged_keep(HeapTag::Map, Self::map_size(size), live)
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *(addr.offset(Self::SIZE_OFFSET) as *mut u32) = size;
+            let map: &HMap = &*(addr as *mut HMap);
+            for i in 0..size << 1 {
+                map.set_slot(i, HeapTag::Nil as u8 as *mut u8);
+            }
+        }
+        addr
+    }
+
+    /// Maps keyed by objects hash their addresses and have to be rehashed
+    /// by the collector after moving, see `CopyGC::visit`.
+    pub fn has_identity_keys(addr: *mut u8) -> bool {
+        HValue::get_repr(addr) & Self::IDENTITY_KEYS_BIT != 0
+    }
+
+    fn is_identity_key(key: *mut u8) -> bool {
+        HValue::is_heap_pointer(key)
+            && !matches!(
+                HValue::get_tag(key),
+                HeapTag::String | HeapTag::Number | HeapTag::BigInt
+            )
+    }
+
+    /// Strings hash by contents, numbers by value and everything else by
+    /// identity.
+    pub fn hash_key(key: *mut u8) -> u32 {
+        if HValue::is_unboxed(key) {
+            return hash_word(key as u64);
+        }
+        match HValue::get_tag(key) {
+            HeapTag::String => unsafe { (*(key as *mut HString)).hash() },
+            HeapTag::Number => hash_word(HNumber::bits(key)),
+            HeapTag::BigInt => HBigInt::hash(key),
+            _ => hash_word(key as u64),
+        }
+    }
+
+    pub fn keys_equal(a: *mut u8, b: *mut u8) -> bool {
+        if a == b {
+            return true;
+        }
+        if HValue::is_unboxed(a) || HValue::is_unboxed(b) {
+            return false;
+        }
+        match (HValue::get_tag(a), HValue::get_tag(b)) {
+            (HeapTag::String, HeapTag::String) => HString::equals(a, b),
+            (HeapTag::Number, HeapTag::Number) => HNumber::bits(a) == HNumber::bits(b),
+            (HeapTag::BigInt, HeapTag::BigInt) => HBigInt::compare(a, b) == Ordering::Equal,
+            _ => false,
+        }
+    }
+
+    fn is_tombstone(&self, index: u32) -> bool {
+        !self.has_slot(index << 1) && self.get_slot((index << 1) + 1) as *mut u8 == Self::TOMBSTONE
+    }
+
+    /// Index of the entry for `key`, or of the slot it would be inserted at.
+    fn find(&self, key: *mut u8) -> (u32, bool) {
+        let mask = self.size() - 1;
+        let mut index = Self::hash_key(key) & mask;
+        let mut insert_at = None;
+        loop {
+            if !self.has_slot(index << 1) {
+                if !self.is_tombstone(index) {
+                    return (insert_at.unwrap_or(index), false);
+                }
+                insert_at = insert_at.or(Some(index));
+            } else if Self::keys_equal(self.get_slot(index << 1) as *mut u8, key) {
+                return (index, true);
+            }
+            index = (index + 1) & mask;
+        }
+    }
+
+    pub fn get(&self, key: *mut u8) -> Option<*mut u8> {
+        match self.find(key) {
+            (index, true) => Some(self.get_slot((index << 1) + 1) as *mut u8),
+            _ => None,
+        }
+    }
+
+    pub fn contains(&self, key: *mut u8) -> bool {
+        self.find(key).1
+    }
+
+    /// Sets `key` to `value`. String keys are interned. Returns the map,
+    /// which is a new, bigger one if `map` had to grow.
+    pub fn set(gc: &mut CopyGC, map: *mut u8, key: *mut u8, value: *mut u8) -> *mut u8 {
+        debug_assert!(
+            key != HeapTag::Nil as u8 as *mut u8,
+            "nil can not be a map key"
+        );
+        let mut live = [map, key, value];
+        if HValue::get_tag(key) == HeapTag::String && !HString::is_interned(key) {
+            for value in live.iter_mut() {
+                gc.add_root(value, RefType::Persistent);
+            }
+            let key = gc.intern(live[1]);
+            for value in live.iter_mut() {
+                gc.remove_root(value);
+            }
+            live[1] = key;
+        }
+
+        let [mut map, key, value] = live;
+        unsafe {
+            let (index, found) = (*(map as *mut HMap)).find(key);
+            if found {
+                (*(map as *mut HMap)).set_slot((index << 1) + 1, value);
+                return map;
+            }
+
+            let table: &HMap = &*(map as *mut HMap);
+            if (table.count() + table.deleted() + 1) * 4 > table.size() * 3 {
+                map = Self::grow(gc, map, &mut [key, value]);
+                return Self::set(gc, map, key, value);
+            }
+
+            let table: &HMap = &*(map as *mut HMap);
+            let reused = table.is_tombstone(index);
+            table.set_slot(index << 1, key);
+            table.set_slot((index << 1) + 1, value);
+            table.set_counts(table.count() + 1, table.deleted() - reused as u32);
+            if Self::is_identity_key(key) {
+                *map.offset(HValue::REPR_OFF) |= Self::IDENTITY_KEYS_BIT;
+            }
+            map
+        }
+    }
+
+    /// Copies the entries of `map` to a new table, twice as big if it is
+    /// more than half full. Tombstones are dropped on the way.
+    fn grow(gc: &mut CopyGC, map: *mut u8, live: &mut [*mut u8]) -> *mut u8 {
+        let (size, count) =
+            unsafe { ((*(map as *mut HMap)).size(), (*(map as *mut HMap)).count()) };
+        let size = if (count + 1) * 2 > size {
+            size << 1
+        } else {
+            size
+        };
+
+        let mut keep: Vec<*mut u8> = vec![map];
+        keep.extend_from_slice(live);
+        let new = Self::new_keep(gc, size, &mut keep);
+        live.copy_from_slice(&keep[1..]);
+        unsafe {
+            let old: &HMap = &*(keep[0] as *mut HMap);
+            let table: &HMap = &*(new as *mut HMap);
+            for (key, value) in old.iter() {
+                table.insert_new(key, value);
+            }
+            *new.offset(HValue::REPR_OFF) = HValue::get_repr(keep[0]);
+        }
+        new
+    }
+
+    /// Inserts a key that is known to be missing, without growing.
+    fn insert_new(&self, key: *mut u8, value: *mut u8) {
+        let mask = self.size() - 1;
+        let mut index = Self::hash_key(key) & mask;
+        while self.has_slot(index << 1) || self.is_tombstone(index) {
+            index = (index + 1) & mask;
+        }
+        self.set_slot(index << 1, key);
+        self.set_slot((index << 1) + 1, value);
+        self.set_counts(self.count() + 1, self.deleted());
+    }
+
+    /// Removes `key`, leaving a tombstone so probing continues past it.
+    pub fn delete(&self, key: *mut u8) -> bool {
+        let (index, found) = self.find(key);
+        if !found {
+            return false;
+        }
+        self.set_slot(index << 1, HeapTag::Nil as u8 as *mut u8);
+        self.set_slot((index << 1) + 1, Self::TOMBSTONE);
+        self.set_counts(self.count() - 1, self.deleted() + 1);
+        true
+    }
+
+    /// Removes all entries, keeping the capacity.
+    pub fn clear(&self) {
+        for i in 0..self.size() << 1 {
+            self.set_slot(i, HeapTag::Nil as u8 as *mut u8);
+        }
+        self.set_counts(0, 0);
+    }
+
+    /// Rebuilds the table in place, after identity keys moved.
+    pub fn rehash(&self) {
+        let entries: Vec<(*mut u8, *mut u8)> = self.iter().collect();
+        self.clear();
+        for (key, value) in entries {
+            self.insert_new(key, value);
+        }
+    }
+
+    /// Live entries in table order.
+    pub fn iter(&self) -> HMapIter {
+        HMapIter {
+            map: self.addr(),
+            index: 0,
+        }
+    }
+
+    pub const SPACE_OFFSET: isize = interior_offset(4);
+    pub const SIZE_OFFSET: isize = interior_offset(1);
+    pub const COUNT_OFFSET: isize = interior_offset(2);
+    pub const DELETED_OFFSET: isize = interior_offset(3);
+    pub const MIN_SIZE: u32 = 8;
+    pub const IDENTITY_KEYS_BIT: u8 = 0x01;
+    /// Value of a deleted entry, its key is nil.
+    pub const TOMBSTONE: *mut u8 = HNumber::tag(1) as *mut u8;
+}
+
+/// Iterator over the entries of an `HMap`, must not be held across
+/// allocations.
+pub struct HMapIter {
+    map: *mut u8,
+    index: u32,
+}
+
+impl Iterator for HMapIter {
+    type Item = (*mut u8, *mut u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let map: &HMap = unsafe { &*(self.map as *mut HMap) };
+        while self.index < map.size() {
+            let index = self.index;
+            self.index += 1;
+            if map.has_slot(index << 1) {
+                return Some((
+                    map.get_slot(index << 1) as *mut u8,
+                    map.get_slot((index << 1) + 1) as *mut u8,
+                ));
+            }
+        }
+        None
+    }
+}
+pub struct HArray(UnsafeCell<()>);
+
+impl HValTrait for HArray {
+    const TAG: HeapTag = HeapTag::Array;
+}
+pub struct HNumber(UnsafeCell<()>);
+
+impl HValTrait for HNumber {
+    const TAG: HeapTag = HeapTag::Number;
+}
+
+#[cfg(all(feature = "nan-boxing", feature = "compressed-pointers"))]
+compile_error!("NaN-boxed doubles need 64 bit fields, `nan-boxing` can not be combined with `compressed-pointers`");
+
+/// Largest integer stored unboxed, results outside of
+/// `MIN_SMALL_INT..=MAX_SMALL_INT` become doubles.
+#[cfg(feature = "nan-boxing")]
+pub const MAX_SMALL_INT: i64 = i32::MAX as i64;
+#[cfg(all(not(feature = "nan-boxing"), feature = "compressed-pointers"))]
+pub const MAX_SMALL_INT: i64 = (1 << 30) - 1;
+#[cfg(all(not(feature = "nan-boxing"), not(feature = "compressed-pointers")))]
+pub const MAX_SMALL_INT: i64 = (1 << 62) - 1;
+pub const MIN_SMALL_INT: i64 = -MAX_SMALL_INT - 1;
+
+/// Tag of unboxed integers when NaN-boxing, the low 32 bits hold the value.
+#[cfg(feature = "nan-boxing")]
+pub const NUMBER_TAG: u64 = 0xfffe_0000_0000_0000;
+/// Added to the bits of a double so that all of them, including negative
+/// NaNs, end up between pointers and `NUMBER_TAG`.
+#[cfg(feature = "nan-boxing")]
+pub const DOUBLE_ENCODE_OFFSET: u64 = 1 << 49;
+
+impl HNumber {
+    pub const VALUE_OFFSET: isize = interior_offset(1);
+
+    #[cfg(not(feature = "nan-boxing"))]
+    pub const fn tag(value: i64) -> i64 {
+        return value << 1;
+    }
+
+    /// Only the low 32 bits of `value` fit an unboxed integer.
+    #[cfg(feature = "nan-boxing")]
+    pub const fn tag(value: i64) -> i64 {
+        return (NUMBER_TAG | value as u32 as u64) as i64;
+    }
+
+    /// Value of an unboxed integer.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn untag(value: *mut u8) -> i64 {
+        value as i64 >> 1
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub fn untag(value: *mut u8) -> i64 {
+        value as u64 as u32 as i32 as i64
+    }
+
+    /// Returns true if `value` is an unboxed integer.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn is_int(value: *mut u8) -> bool {
+        HValue::is_unboxed(value)
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub fn is_int(value: *mut u8) -> bool {
+        value as u64 & NUMBER_TAG == NUMBER_TAG
+    }
+
+    /// Encodes `value` without allocating. Integral values that fit 32 bits
+    /// become unboxed integers and all NaNs are canonicalized, so equal
+    /// numbers always have equal encodings.
+    #[cfg(feature = "nan-boxing")]
+    pub fn from_double(value: f64) -> *mut u8 {
+        if value as i32 as f64 == value && !(value == 0.0 && value.is_sign_negative()) {
+            return Self::tag(value as i32 as i64) as *mut u8;
+        }
+        let bits = if value.is_nan() {
+            f64::NAN.to_bits()
+        } else {
+            value.to_bits()
+        };
+        bits.wrapping_add(DOUBLE_ENCODE_OFFSET) as *mut u8
+    }
+
+    /// Returns true if `value` is an unboxed double.
+    #[cfg(feature = "nan-boxing")]
+    pub fn is_double(value: *mut u8) -> bool {
+        HValue::is_unboxed(value) && !Self::is_int(value)
+    }
+
+    /// Numeric value of a number in any representation.
+    pub fn to_double(value: *mut u8) -> f64 {
+        if Self::is_int(value) {
+            return Self::untag(value) as f64;
+        }
+        #[cfg(feature = "nan-boxing")]
+        {
+            if HValue::is_unboxed(value) {
+                return f64::from_bits((value as u64).wrapping_sub(DOUBLE_ENCODE_OFFSET));
+            }
+        }
+        if HValue::get_tag(value) == HeapTag::BigInt {
+            return HBigInt::to_double(value);
+        }
+        f64::from_bits(Self::bits(value))
+    }
+
+    /// Raw 64 bits of a boxed number.
+    pub fn bits(addr: *mut u8) -> u64 {
+        unsafe { *(addr.offset(Self::VALUE_OFFSET) as *mut u64) }
+    }
+
+    pub fn is_number(value: *mut u8) -> bool {
+        HValue::get_tag(value) == HeapTag::Number
+    }
+
+    /// Returns true if `value` is integral and inside of the small integer
+    /// range.
+    #[cfg(not(feature = "nan-boxing"))]
+    fn fits_int(value: f64) -> bool {
+        value.fract() == 0.0
+            && value >= MIN_SMALL_INT as f64
+            && value < -(MIN_SMALL_INT as f64)
+            && !(value == 0.0 && value.is_sign_negative())
+    }
+
+    /// Number holding `value`, a heap double if it does not fit a small
+    /// integer.
+    pub fn from_i64(gc: &mut CopyGC, value: i64) -> *mut u8 {
+        if (MIN_SMALL_INT..=MAX_SMALL_INT).contains(&value) {
+            Self::tag(value) as *mut u8
+        } else {
+            Self::new(gc, value as f64)
+        }
+    }
+
+    /// Number holding `value`. Integral values in the small integer range
+    /// are tagged and NaNs canonicalized, everything else is allocated as a
+    /// heap double unless doubles are NaN-boxed.
+    #[cfg(not(feature = "nan-boxing"))]
+    pub fn new(gc: &mut CopyGC, value: f64) -> *mut u8 {
+        if Self::fits_int(value) {
+            return Self::tag(value as i64) as *mut u8;
+        }
+        let bits = if value.is_nan() {
+            f64::NAN.to_bits()
+        } else {
+            value.to_bits()
+        };
+        let number = gc.alloc_tagged(HeapTag::Number, 8).to_mut_ptr::<u8>();
+        unsafe {
+            *(number.offset(Self::VALUE_OFFSET) as *mut u64) = bits;
+        }
+        number
+    }
+
+    #[cfg(feature = "nan-boxing")]
+    pub fn new(_gc: &mut CopyGC, value: f64) -> *mut u8 {
+        Self::from_double(value)
+    }
+
+    /// Applies `op` to two numbers. Small integer operands stay small
+    /// integers as long as the exact result fits, on overflow, inexact
+    /// division or a negative zero the result is a double. In strict integer
+    /// mode overflowing results become BigInts instead, and BigInt operands
+    /// use BigInt arithmetic unless the other operand is a double.
+    pub fn binop(gc: &mut CopyGC, op: NumberOp, a: *mut u8, b: *mut u8) -> *mut u8 {
+        if Self::is_int(a) && Self::is_int(b) {
+            let (x, y) = (Self::untag(a) as i128, Self::untag(b) as i128);
+            let exact = match op {
+                NumberOp::Add => Some(x + y),
+                NumberOp::Sub => Some(x - y),
+                NumberOp::Mul if x * y != 0 || (x >= 0 && y >= 0) => Some(x * y),
+                NumberOp::Div if y != 0 && x % y == 0 && !(x == 0 && y < 0) => Some(x / y),
+                NumberOp::Mod if y != 0 && !(x < 0 && x % y == 0) => Some(x % y),
+                _ => None,
+            };
+            if let Some(result) = exact {
+                if result >= MIN_SMALL_INT as i128 && result <= MAX_SMALL_INT as i128 {
+                    return Self::tag(result as i64) as *mut u8;
+                }
+                if gc.strict_integers() {
+                    return HBigInt::from_i128(gc, result);
+                }
+            }
+        } else if HBigInt::is_integer(a) && HBigInt::is_integer(b) {
+            let result = match op {
+                NumberOp::Add => Some(HBigInt::add(gc, a, b)),
+                NumberOp::Sub => Some(HBigInt::sub(gc, a, b)),
+                NumberOp::Mul => Some(HBigInt::mul(gc, a, b)),
+                NumberOp::Div => HBigInt::div(gc, a, b),
+                NumberOp::Mod => HBigInt::rem(gc, a, b),
+            };
+            if let Some(result) = result {
+                return result;
+            }
+        }
+
+        let (x, y) = (Self::to_double(a), Self::to_double(b));
+        let result = match op {
+            NumberOp::Add => x + y,
+            NumberOp::Sub => x - y,
+            NumberOp::Mul => x * y,
+            NumberOp::Div => x / y,
+            NumberOp::Mod => x % y,
+        };
+        Self::new(gc, result)
+    }
+
+    /// Numeric ordering of two numbers, `None` if either is NaN.
+    pub fn compare(a: *mut u8, b: *mut u8) -> Option<Ordering> {
+        if Self::is_int(a) && Self::is_int(b) {
+            return Some(Self::untag(a).cmp(&Self::untag(b)));
+        }
+        if HBigInt::is_integer(a) && HBigInt::is_integer(b) {
+            return Some(HBigInt::compare(a, b));
+        }
+        Self::to_double(a).partial_cmp(&Self::to_double(b))
+    }
+
+    /// Shortest decimal string that parses back to `value`. Magnitudes of
+    /// `1e21` and more and below `1e-6` are written with an exponent.
+    pub fn format_double(value: f64) -> String {
+        if value.is_nan() {
+            return "NaN".to_string();
+        }
+        if value.is_infinite() {
+            return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+        }
+        if value == 0.0 {
+            return "0".to_string();
+        }
+
+        // `{:e}` gives the shortest round-trip digits, like `1.25e-3`
+        let formatted = format!("{:e}", value.abs());
+        let (mantissa, exponent) = formatted.split_at(formatted.find('e').unwrap());
+        let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+        let (k, n) = (
+            digits.len() as i32,
+            exponent[1..].parse::<i32>().unwrap() + 1,
+        );
+        let body = if k <= n && n <= 21 {
+            format!("{}{}", digits, "0".repeat((n - k) as usize))
+        } else if 0 < n && n <= 21 {
+            format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+        } else if -6 < n && n <= 0 {
+            format!("0.{}{}", "0".repeat(-n as usize), digits)
+        } else {
+            let sign = if n > 0 { '+' } else { '-' };
+            let fraction = if k > 1 {
+                format!(".{}", &digits[1..])
+            } else {
+                String::new()
+            };
+            format!("{}{}e{}{}", &digits[..1], fraction, sign, (n - 1).abs())
+        };
+        if value < 0.0 {
+            format!("-{}", body)
+        } else {
+            body
+        }
+    }
+
+    /// Formats the integer `value` in `radix`, between 2 and 36.
+    pub fn format_int(value: i64, radix: u32) -> String {
+        let magnitude = bignum::from_u128((value as i128).unsigned_abs());
+        let digits = bignum::to_string_radix(&magnitude, radix);
+        if value < 0 {
+            format!("-{}", digits)
+        } else {
+            digits
+        }
+    }
+
+    /// Formats a number, BigInts included, in `radix`. Integral doubles are
+    /// formatted exactly in any radix, other doubles always in decimal.
+    pub fn to_string_radix(value: *mut u8, radix: u32) -> String {
+        if Self::is_int(value) {
+            return Self::format_int(Self::untag(value), radix);
+        }
+        if HBigInt::is_integer(value) {
+            return HBigInt::to_string_radix(value, radix);
+        }
+        let double = Self::to_double(value);
+        if radix == 10 || !double.is_finite() || double.fract() != 0.0 || double == 0.0 {
+            return Self::format_double(double);
+        }
+
+        // integral doubles are `mantissa * 2^shift` with a non-negative
+        // integer result, so shifting is exact
+        let bits = double.abs().to_bits();
+        let mantissa = (bits & ((1 << 52) - 1)) | (1 << 52);
+        let shift = ((bits >> 52) & 0x7ff) as i32 - 1075;
+        let magnitude = bignum::from_u128(mantissa as u128);
+        let magnitude = if shift >= 0 {
+            bignum::shl(&magnitude, shift as u32)
+        } else {
+            bignum::shr(&magnitude, -shift as u32).0
+        };
+        let digits = bignum::to_string_radix(&magnitude, radix);
+        if double < 0.0 {
+            format!("-{}", digits)
+        } else {
+            digits
+        }
+    }
+
+    /// Parses an optionally signed number: a decimal integer, a hex integer
+    /// with a `0x` prefix, a decimal with fraction and exponent, or
+    /// `Infinity`. Integers outside of the small integer range become
+    /// BigInts in strict integer mode and doubles otherwise. `None` if
+    /// `text` is anything else, whitespace included.
+    pub fn parse(gc: &mut CopyGC, text: &str) -> Option<*mut u8> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let sign = if negative { -1.0 } else { 1.0 };
+        if text == "Infinity" {
+            return Some(Self::new(gc, sign * f64::INFINITY));
+        }
+
+        let (digits, radix) = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => (hex, 16),
+            None => (text, 10),
+        };
+        if let Some(magnitude) = bignum::from_str_radix(digits, radix) {
+            let small = bignum::to_u128(&magnitude).filter(|&m| m <= MAX_SMALL_INT as u128);
+            if let Some(small) = small {
+                if !(negative && small == 0) {
+                    let small = small as i64;
+                    return Some(Self::tag(if negative { -small } else { small }) as *mut u8);
+                }
+            } else if gc.strict_integers() {
+                return Some(HBigInt::new(gc, negative, magnitude));
+            }
+            return Some(Self::new(gc, sign * bignum::to_f64(&magnitude)));
+        }
+
+        // `str::parse` also takes `inf` and `NaN`, only allow decimals
+        let decimal = text.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+            && text
+                .chars()
+                .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+            && text.chars().any(|c| c.is_ascii_digit());
+        if radix != 10 || !decimal {
+            return None;
+        }
+        let value: f64 = text.parse().ok()?;
+        Some(Self::new(gc, sign * value))
+    }
+}
+
+pub struct HBoolean(UnsafeCell<()>);
+
+/// Booleans are the immediates `TRUE` and `FALSE`.
+impl HBoolean {
+    #[inline]
+    pub fn new(value: bool) -> *mut u8 {
+        if value {
+            TRUE
+        } else {
+            FALSE
+        }
+    }
+
+    #[inline]
+    pub fn is_boolean(value: *mut u8) -> bool {
+        value == TRUE || value == FALSE
+    }
+
+    #[inline]
+    pub fn value(value: *mut u8) -> bool {
+        debug_assert!(Self::is_boolean(value));
+        value == TRUE
+    }
+}
+
+pub struct HBigInt(UnsafeCell<()>);
+
+impl HValTrait for HBigInt {
+    const TAG: HeapTag = HeapTag::BigInt;
+}
+
+/// Arbitrary precision integer, the sign lives in the repr byte and the
+/// magnitude is stored as little endian 32 bit digits.
+///
+/// Integers inside of the small integer range are never BigInts, results
+/// that fit are returned as small integers so every integer has exactly one
+/// representation. All operations accept small integers as operands.
+impl HBigInt {
+    pub const LENGTH_OFFSET: isize = interior_offset(1);
+    pub const DIGITS_OFFSET: isize = interior_offset(2);
+    const NEGATIVE_BIT: u8 = 0x01;
+
+    /// Payload size of a BigInt with `length` digits.
+    pub fn digits_size(length: u32) -> usize {
+        FIELD_SIZE + crate::mem::align_usize(length as usize * 4, 8)
+    }
+
+    /// Integer with the given sign and magnitude.
+    pub fn new(gc: &mut CopyGC, negative: bool, digits: Digits) -> *mut u8 {
+        let digits = bignum::normalize(digits);
+        if let Some(magnitude) = bignum::to_u128(&digits) {
+            // compare magnitudes, negating first overflows for 2^127
+            let limit = if negative {
+                -(MIN_SMALL_INT as i128)
+            } else {
+                MAX_SMALL_INT as i128
+            };
+            if magnitude <= limit as u128 {
+                let value = magnitude as i128;
+                return HNumber::tag(if negative { -value } else { value } as i64) as *mut u8;
+            }
+        }
+
+        let addr = gc
+            .alloc_tagged(HeapTag::BigInt, Self::digits_size(digits.len() as u32))
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *addr.offset(HValue::REPR_OFF) = if negative { Self::NEGATIVE_BIT } else { 0 };
+            *(addr.offset(Self::LENGTH_OFFSET) as *mut u32) = digits.len() as u32;
+            std::ptr::copy_nonoverlapping(
+                digits.as_ptr(),
+                addr.offset(Self::DIGITS_OFFSET) as *mut u32,
+                digits.len(),
+            );
+        }
+        addr
+    }
+
+    pub fn from_i128(gc: &mut CopyGC, value: i128) -> *mut u8 {
+        let magnitude = if value < 0 {
+            (value as u128).wrapping_neg()
+        } else {
+            value as u128
+        };
+        Self::new(gc, value < 0, bignum::from_u128(magnitude))
+    }
+
+    /// Returns true for small integers and BigInts.
+    pub fn is_integer(value: *mut u8) -> bool {
+        HNumber::is_int(value) || HValue::get_tag(value) == HeapTag::BigInt
+    }
+
+    /// Number of digits.
+    pub fn length(addr: *mut u8) -> u32 {
+        unsafe { *(addr.offset(Self::LENGTH_OFFSET) as *mut u32) }
+    }
+
+    pub fn is_negative(addr: *mut u8) -> bool {
+        HValue::get_repr(addr) & Self::NEGATIVE_BIT != 0
+    }
+
+    /// Sign and magnitude of a small integer or BigInt, copied out of the
+    /// heap so that it stays valid across allocations.
+    pub fn parts(value: *mut u8) -> (bool, Digits) {
+        if HNumber::is_int(value) {
+            let value = HNumber::untag(value);
+            return (value < 0, bignum::from_u128((value as i128).unsigned_abs()));
+        }
+        let digits = unsafe {
+            std::slice::from_raw_parts(
+                value.offset(Self::DIGITS_OFFSET) as *const u32,
+                Self::length(value) as usize,
+            )
+        };
+        (Self::is_negative(value), digits.to_vec())
+    }
+
+    pub fn add(gc: &mut CopyGC, a: *mut u8, b: *mut u8) -> *mut u8 {
+        let (a_negative, a) = Self::parts(a);
+        let (b_negative, b) = Self::parts(b);
+        Self::add_parts(gc, a_negative, &a, b_negative, &b)
+    }
+
+    pub fn sub(gc: &mut CopyGC, a: *mut u8, b: *mut u8) -> *mut u8 {
+        let (a_negative, a) = Self::parts(a);
+        let (b_negative, b) = Self::parts(b);
+        Self::add_parts(gc, a_negative, &a, !b_negative, &b)
+    }
+
+    fn add_parts(
+        gc: &mut CopyGC,
+        a_negative: bool,
+        a: &[u32],
+        b_negative: bool,
+        b: &[u32],
+    ) -> *mut u8 {
+        if a_negative == b_negative {
+            return Self::new(gc, a_negative, bignum::add(a, b));
+        }
+        match bignum::compare(a, b) {
+            Ordering::Less => Self::new(gc, b_negative, bignum::sub(b, a)),
+            _ => Self::new(gc, a_negative, bignum::sub(a, b)),
+        }
+    }
+
+    pub fn mul(gc: &mut CopyGC, a: *mut u8, b: *mut u8) -> *mut u8 {
+        let (a_negative, a) = Self::parts(a);
+        let (b_negative, b) = Self::parts(b);
+        Self::new(gc, a_negative != b_negative, bignum::mul(&a, &b))
+    }
+
+    /// Quotient rounded towards zero, `None` when dividing by zero.
+    pub fn div(gc: &mut CopyGC, a: *mut u8, b: *mut u8) -> Option<*mut u8> {
+        let (a_negative, a) = Self::parts(a);
+        let (b_negative, b) = Self::parts(b);
+        if b.is_empty() {
+            return None;
+        }
+        let (quotient, _) = bignum::div_rem(&a, &b);
+        Some(Self::new(gc, a_negative != b_negative, quotient))
+    }
+
+    /// Remainder of `div`, it has the sign of `a`.
+    pub fn rem(gc: &mut CopyGC, a: *mut u8, b: *mut u8) -> Option<*mut u8> {
+        let (a_negative, a) = Self::parts(a);
+        let (_, b) = Self::parts(b);
+        if b.is_empty() {
+            return None;
+        }
+        let (_, rem) = bignum::div_rem(&a, &b);
+        Some(Self::new(gc, a_negative, rem))
+    }
+
+    pub fn shl(gc: &mut CopyGC, a: *mut u8, bits: u32) -> *mut u8 {
+        let (negative, a) = Self::parts(a);
+        Self::new(gc, negative, bignum::shl(&a, bits))
+    }
+
+    /// Arithmetic right shift, rounding towards negative infinity.
+    pub fn shr(gc: &mut CopyGC, a: *mut u8, bits: u32) -> *mut u8 {
+        let (negative, a) = Self::parts(a);
+        let (mut result, lost) = bignum::shr(&a, bits);
+        if negative && lost {
+            result = bignum::add(&result, &[1]);
+        }
+        Self::new(gc, negative, result)
+    }
+
+    pub fn compare(a: *mut u8, b: *mut u8) -> Ordering {
+        let (a_negative, a) = Self::parts(a);
+        let (b_negative, b) = Self::parts(b);
+        match (a_negative, b_negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => bignum::compare(&a, &b),
+            (true, true) => bignum::compare(&b, &a),
+        }
+    }
+
+    pub fn to_double(value: *mut u8) -> f64 {
+        let (negative, digits) = Self::parts(value);
+        let magnitude = bignum::to_f64(&digits);
+        if negative {
+            -magnitude
+        } else {
+            magnitude
+        }
+    }
+
+    /// Formats an integer in `radix`, e.g. 10 or 16, without prefix.
+    pub fn to_string_radix(value: *mut u8, radix: u32) -> String {
+        let (negative, digits) = Self::parts(value);
+        let digits = bignum::to_string_radix(&digits, radix);
+        if negative {
+            format!("-{}", digits)
+        } else {
+            digits
+        }
+    }
+
+    /// Parses an optionally signed decimal integer, or a hex one with a `0x`
+    /// prefix.
+    pub fn parse(gc: &mut CopyGC, text: &str) -> Option<*mut u8> {
+        let (negative, text) = match text.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let digits = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            Some(hex) => bignum::from_str_radix(hex, 16)?,
+            None => bignum::from_str_radix(text, 10)?,
+        };
+        Some(Self::new(gc, negative, digits))
+    }
+
+    /// Hash of the value, equal integers hash equal.
+    pub fn hash(value: *mut u8) -> u32 {
+        let (negative, digits) = Self::parts(value);
+        digits.iter().fold(negative as u32, |hash, &digit| {
+            hash_word((hash as u64) << 32 | digit as u64)
+        })
+    }
+}
+
+/// Arithmetic done by `BinOPNumber`.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, PartialOrd, Ord, Eq)]
+pub enum NumberOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl HArray {
+    /// Allocates an empty array with room for `capacity` dense elements.
+    pub fn new(gc: &mut CopyGC, capacity: u32) -> *mut u8 {
+        let obj = gc
+            .alloc_tagged(HeapTag::Array, 5 * FIELD_SIZE)
+            .to_mut_ptr::<u8>();
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        HObject::set_map_s(obj, nil);
+        HObject::store_proto(obj, nil);
+        Self::set_elements(obj, nil);
+
+        let mut live = [obj];
+        let elements = HElements::new_keep(gc, capacity, &mut live);
+        Self::set_elements(live[0], elements);
+        live[0]
+    }
+
+    /// Length of the array. With `shrink` trailing holes are cut off first,
+    /// unless the array is frozen.
+    pub fn length(obj: *mut u8, shrink: bool) -> usize {
+        let mut result = unsafe { *(obj.offset(Self::LENGTH_OFFSET) as *mut u32) as usize };
+        if shrink && !HObject::is_frozen(obj) {
+            let mut shrinked = result;
+            while shrinked > 0 && !Self::has(obj, shrinked as u32 - 1) {
+                shrinked -= 1;
+            }
+            if result != shrinked {
+                result = shrinked;
+                Self::truncate(obj, result);
+            }
+        }
+        result
+    }
+
+    /// Sets the length, elements at or past `len` are removed. Throws if
+    /// the array is frozen, or sealed and elements would be removed, or if
+    /// `len` is past `MAX_INDEX + 1`.
+    pub fn set_length(gc: &mut CopyGC, obj: *mut u8, len: usize) -> Result<(), *mut u8> {
+        let old = Self::length(obj, false);
+        let message = if len > Self::MAX_INDEX as usize + 1 {
+            Some("array length out of range")
+        } else if HObject::is_frozen(obj) && len != old {
+            Some("cannot change the length of a frozen array")
+        } else if HObject::is_sealed(obj) && Self::indices(obj).iter().any(|&i| i as usize >= len) {
+            Some("cannot remove an element of a sealed array")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return Err(HString::from_str(gc, message));
+        }
+        Self::truncate(obj, len);
+        Ok(())
+    }
+
+    /// `set_length` without checking the object flags.
+    fn truncate(obj: *mut u8, len: usize) {
+        let old = unsafe { *(obj.offset(Self::LENGTH_OFFSET) as *mut u32) as usize };
+        if len < old {
+            match Self::storage(obj) {
+                Storage::Dense(elements) => {
+                    let elements = unsafe { &*elements };
+                    for i in len as u32..std::cmp::min(old as u32, elements.capacity()) {
+                        elements.set(i, Self::hole());
+                    }
+                }
+                Storage::Sparse(map) => {
+                    let map = unsafe { &*map };
+                    let removed: Vec<*mut u8> = map
+                        .iter()
+                        .map(|(key, _)| key)
+                        .filter(|&key| Self::index_of(key) >= len as u32)
+                        .collect();
+                    for key in removed {
+                        map.delete(key);
+                    }
+                }
+            }
+        }
+        unsafe {
+            *(obj.offset(Self::LENGTH_OFFSET) as *mut u32) = len as u32;
+        }
+    }
+
+    /// Missing elements are stored as `HOLE`, which never leaves the array.
+    fn hole() -> *mut u8 {
+        HOLE
+    }
+
+    fn index_key(index: u32) -> *mut u8 {
+        HNumber::tag(index as i64) as *mut u8
+    }
+
+    fn index_of(key: *mut u8) -> u32 {
+        HNumber::untag(key) as u32
+    }
+
+    pub fn elements_slot(obj: *mut u8) -> *mut *mut u8 {
+        unsafe { obj.offset(Self::ELEMENTS_OFFSET) as *mut *mut u8 }
+    }
+
+    pub fn elements(obj: *mut u8) -> *mut u8 {
+        unsafe { load_field(Self::elements_slot(obj)) }
+    }
+
+    fn set_elements(obj: *mut u8, elements: *mut u8) {
+        unsafe { store_field(Self::elements_slot(obj), elements) }
+    }
+
+    fn storage(obj: *mut u8) -> Storage {
+        let elements = Self::elements(obj);
+        if HValue::get_tag(elements) == HeapTag::Map {
+            Storage::Sparse(elements as *mut HMap)
+        } else {
+            Storage::Dense(elements as *mut HElements)
+        }
+    }
+
+    /// Sparse arrays keep their elements in an `HMap` keyed by index.
+    pub fn is_sparse(obj: *mut u8) -> bool {
+        match Self::storage(obj) {
+            Storage::Sparse(_) => true,
+            Storage::Dense(_) => false,
+        }
+    }
+
+    /// Element at `index`, `UNDEFINED` for holes and indices past the end.
+    pub fn get(obj: *mut u8, index: u32) -> *mut u8 {
+        match Self::element(obj, index) {
+            HOLE => UNDEFINED,
+            value => value,
+        }
+    }
+
+    /// Whether the element at `index` is present, holes are not.
+    pub fn has(obj: *mut u8, index: u32) -> bool {
+        Self::element(obj, index) != Self::hole()
+    }
+
+    /// Element at `index` as stored, `hole()` if missing.
+    fn element(obj: *mut u8, index: u32) -> *mut u8 {
+        if index as usize >= Self::length(obj, false) {
+            return Self::hole();
+        }
+        match Self::storage(obj) {
+            Storage::Dense(elements) => unsafe {
+                if index < (*elements).capacity() {
+                    (*elements).get(index)
+                } else {
+                    Self::hole()
+                }
+            },
+            Storage::Sparse(map) => {
+                unsafe { (*map).get(Self::index_key(index)) }.unwrap_or_else(Self::hole)
+            }
+        }
+    }
+
+    /// Stores `value` at `index`, growing `length` as needed. The dense
+    /// backing store grows geometrically, unless the store would leave more
+    /// than `DENSE_LENGTH_MAX` holes, then the array becomes sparse.
+    /// Returns the array, which may have been moved by a collection, or an
+    /// exception if the array is frozen, `index` is a new element of a
+    /// non-extensible array or past `MAX_INDEX`.
+    pub fn set(
+        gc: &mut CopyGC,
+        obj: *mut u8,
+        index: u32,
+        value: *mut u8,
+    ) -> Result<*mut u8, *mut u8> {
+        if index > Self::MAX_INDEX {
+            return Err(HString::from_str(gc, "array index out of range"));
+        }
+        if HObject::is_frozen(obj) {
+            return Err(HString::from_str(
+                gc,
+                "cannot change an element of a frozen array",
+            ));
+        }
+        if !HObject::is_extensible(obj) && !Self::has(obj, index) {
+            let message = "cannot add an element to a non-extensible array";
+            return Err(HString::from_str(gc, message));
+        }
+        Ok(Self::store(gc, obj, index, value))
+    }
+
+    /// `set` without checking the object flags.
+    fn store(gc: &mut CopyGC, obj: *mut u8, index: u32, value: *mut u8) -> *mut u8 {
+        let length = Self::length(obj, false);
+        let mut live = [obj, value];
+        if let Storage::Dense(elements) = Self::storage(obj) {
+            let capacity = unsafe { (*elements).capacity() };
+            if index >= capacity {
+                if index as usize > length + Self::DENSE_LENGTH_MAX {
+                    Self::make_sparse(gc, &mut live);
+                } else {
+                    let capacity = std::cmp::max(index + 1, capacity * 2);
+                    Self::grow(gc, capacity, &mut live);
+                }
+            }
+        }
+
+        let [mut obj, value] = live;
+        match Self::storage(obj) {
+            Storage::Dense(elements) => unsafe { (*elements).set(index, value) },
+            Storage::Sparse(map) => {
+                gc.add_root(&mut obj, RefType::Persistent);
+                let map = HMap::set(gc, map as *mut u8, Self::index_key(index), value);
+                gc.remove_root(&mut obj);
+                Self::set_elements(obj, map);
+            }
+        }
+        if index as usize >= length {
+            unsafe {
+                *(obj.offset(Self::LENGTH_OFFSET) as *mut u32) = index + 1;
+            }
+        }
+        obj
+    }
+
+    /// Copies the dense elements of `live[0]` to a store of `capacity`.
+    fn grow(gc: &mut CopyGC, capacity: u32, live: &mut [*mut u8]) {
+        let new = HElements::new_keep(gc, capacity, live);
+        let obj = live[0];
+        if let Storage::Dense(old) = Self::storage(obj) {
+            let old = unsafe { &*old };
+            let new_elements: &HElements = unsafe { &*(new as *mut HElements) };
+            for i in 0..old.capacity() {
+                new_elements.set(i, old.get(i));
+            }
+        }
+        Self::set_elements(obj, new);
+    }
+
+    /// Moves the dense elements of `live[0]` into an `HMap`.
+    fn make_sparse(gc: &mut CopyGC, live: &mut [*mut u8]) {
+        let capacity = match Self::storage(live[0]) {
+            Storage::Dense(elements) => unsafe { (*elements).capacity() },
+            Storage::Sparse(_) => return,
+        };
+        let mut map = HMap::new_keep(gc, capacity * 2, live);
+        let obj = live[0];
+        if let Storage::Dense(elements) = Self::storage(obj) {
+            for i in 0..capacity {
+                let value = unsafe { (*elements).get(i) };
+                if value != Self::hole() {
+                    // the map is big enough, so this never allocates
+                    map = HMap::set(gc, map, Self::index_key(i), value);
+                }
+            }
+        }
+        Self::set_elements(obj, map);
+    }
+
+    pub fn push(gc: &mut CopyGC, obj: *mut u8, value: *mut u8) -> Result<*mut u8, *mut u8> {
+        let length = Self::length(obj, false) as u32;
+        Self::set(gc, obj, length, value)
+    }
+
+    /// Removes and returns the last element, `UNDEFINED` for empty arrays
+    /// and holes. Sealed arrays throw.
+    pub fn pop(gc: &mut CopyGC, obj: *mut u8) -> Result<*mut u8, *mut u8> {
+        let length = Self::length(obj, false);
+        if length == 0 {
+            return Ok(UNDEFINED);
+        }
+        if HObject::is_sealed(obj) {
+            return Err(HString::from_str(
+                gc,
+                "cannot remove an element of a sealed array",
+            ));
+        }
+        let value = Self::get(obj, length as u32 - 1);
+        Self::truncate(obj, length - 1);
+        Ok(value)
+    }
+
+    /// Indices of the present elements, in order.
+    pub fn indices(obj: *mut u8) -> Vec<u32> {
+        Self::entries(obj)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Present elements with their indices, in index order.
+    fn entries(obj: *mut u8) -> Vec<(u32, *mut u8)> {
+        let length = Self::length(obj, false) as u32;
+        let mut entries: Vec<(u32, *mut u8)> = match Self::storage(obj) {
+            Storage::Dense(elements) => {
+                let elements = unsafe { &*elements };
+                (0..std::cmp::min(length, elements.capacity()))
+                    .map(|i| (i, elements.get(i)))
+                    .filter(|&(_, value)| value != Self::hole())
+                    .collect()
+            }
+            Storage::Sparse(map) => unsafe { &*map }
+                .iter()
+                .map(|(key, value)| (Self::index_of(key), value))
+                .collect(),
+        };
+        entries.sort_by_key(|&(index, _)| index);
+        entries
+    }
+
+    /// Removes `delete_count` elements at `start` and inserts `items` in
+    /// their place, like `Array.prototype.splice`. Returns the array and a
+    /// new array holding the removed elements, both may have been moved by
+    /// a collection. Throws if the flags of the array forbid the change.
+    pub fn splice(
+        gc: &mut CopyGC,
+        obj: *mut u8,
+        start: u32,
+        delete_count: u32,
+        items: &[*mut u8],
+    ) -> Result<(*mut u8, *mut u8), *mut u8> {
+        let length = Self::length(obj, false) as u32;
+        let start = std::cmp::min(start, length);
+        let end = start + std::cmp::min(delete_count, length - start);
+        let inserted = items.len() as u32;
+        let message = if length as u64 - (end - start) as u64 + inserted as u64
+            > Self::MAX_INDEX as u64 + 1
+        {
+            Some("array length out of range")
+        } else if HObject::is_frozen(obj) && (end > start || inserted > 0) {
+            Some("cannot change an element of a frozen array")
+        } else if HObject::is_sealed(obj) && end - start > inserted {
+            Some("cannot remove an element of a sealed array")
+        } else if !HObject::is_extensible(obj) && inserted > end - start {
+            Some("cannot add an element to a non-extensible array")
+        } else {
+            None
+        };
+        if let Some(message) = message {
+            return Err(HString::from_str(gc, message));
+        }
+
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for (index, value) in Self::entries(obj) {
+            if index < start {
+                kept.push((index, value));
+            } else if index < end {
+                removed.push((index - start, value));
+            } else {
+                kept.push((index - (end - start) + inserted, value));
+            }
+        }
+        for (i, &item) in items.iter().enumerate() {
+            kept.push((start + i as u32, item));
+        }
+
+        // everything read from the array has to survive the allocations
+        let mut live: Vec<*mut u8> = vec![obj, Self::hole()];
+        live.extend(kept.iter().map(|&(_, value)| value));
+        live.extend(removed.iter().map(|&(_, value)| value));
+        for value in live.iter_mut() {
+            gc.add_root(value, RefType::Persistent);
+        }
+
+        let mut result = Self::new(gc, std::cmp::max(end - start, 1));
+        live[1] = result;
+        for (i, &(index, _)) in removed.iter().enumerate() {
+            let value = live[2 + kept.len() + i];
+            result = Self::store(gc, live[1], index, value);
+            live[1] = result;
+        }
+        unsafe {
+            *(live[1].offset(Self::LENGTH_OFFSET) as *mut u32) = end - start;
+        }
+
+        let new_length = length - (end - start) + inserted;
+        let dense = kept.len() + Self::DENSE_LENGTH_MAX >= new_length as usize;
+        let elements = HElements::new(gc, if dense { new_length } else { 0 });
+        Self::set_elements(live[0], elements);
+        unsafe {
+            *(live[0].offset(Self::LENGTH_OFFSET) as *mut u32) = 0;
+        }
+        for (i, &(index, _)) in kept.iter().enumerate() {
+            live[0] = Self::store(gc, live[0], index, live[2 + i]);
+        }
+        unsafe {
+            *(live[0].offset(Self::LENGTH_OFFSET) as *mut u32) = new_length;
+        }
+
+        for value in live.iter_mut() {
+            gc.remove_root(value);
+        }
+        Ok((live[0], live[1]))
+    }
+
+    pub const VAR_ARG_LEN: usize = 16;
+    pub const DENSE_LENGTH_MAX: usize = 128;
+    /// Largest element index. Sparse arrays key their elements by unboxed
+    /// integers, which are narrower than 32 bits with NaN-boxing or
+    /// compressed pointers.
+    #[cfg(any(feature = "nan-boxing", feature = "compressed-pointers"))]
+    pub const MAX_INDEX: u32 = MAX_SMALL_INT as u32;
+    #[cfg(not(any(feature = "nan-boxing", feature = "compressed-pointers")))]
+    pub const MAX_INDEX: u32 = u32::MAX - 1;
+    pub const LENGTH_OFFSET: isize = interior_offset(4);
+    pub const ELEMENTS_OFFSET: isize = interior_offset(5);
+}
+
+/// Element storage of an array. The stores move when garbage is collected,
+/// so `storage` has to be called again after allocating.
+enum Storage {
+    Dense(*mut HElements),
+    Sparse(*mut HMap),
+}
+
+pub struct HElements(UnsafeCell<()>);
+
+impl HValTrait for HElements {
+    const TAG: HeapTag = HeapTag::Elements;
+}
+
+/// Dense backing store of an array, holes are `HOLE`.
+impl HElements {
+    pub const CAPACITY_OFFSET: isize = interior_offset(1);
+    pub const SPACE_OFFSET: isize = interior_offset(2);
+    pub const MIN_CAPACITY: u32 = 4;
+
+    pub fn new(gc: &mut CopyGC, capacity: u32) -> *mut u8 {
+        Self::new_keep(gc, capacity, &mut [])
+    }
+
+    fn new_keep(gc: &mut CopyGC, capacity: u32, live: &mut [*mut u8]) -> *mut u8 {
+        let capacity = std::cmp::max(capacity, Self::MIN_CAPACITY);
+        let addr = gc
+            .alloc_tagged_keep(HeapTag::Elements, Self::elements_size(capacity), live)
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *(addr.offset(Self::CAPACITY_OFFSET) as *mut u32) = capacity;
+            let elements: &HElements = &*(addr as *mut HElements);
+            for i in 0..capacity {
+                elements.set(i, HOLE);
+            }
+        }
+        addr
+    }
+
+    /// Payload size of a store with room for `capacity` elements.
+    pub fn elements_size(capacity: u32) -> usize {
+        (1 + capacity as usize) * FIELD_SIZE
+    }
+
+    pub fn capacity(&self) -> u32 {
+        unsafe { *(self.addr().offset(Self::CAPACITY_OFFSET) as *mut u32) }
+    }
+
+    pub fn slot_address(&self, index: u32) -> *mut *mut u8 {
+        debug_assert!(index < self.capacity());
+        unsafe {
+            self.addr()
+                .offset(Self::SPACE_OFFSET + index as isize * FIELD_SIZE as isize)
+                as *mut *mut u8
+        }
+    }
+
+    pub fn get(&self, index: u32) -> *mut u8 {
+        unsafe { load_field(self.slot_address(index)) }
+    }
+
+    pub fn set(&self, index: u32, value: *mut u8) {
+        unsafe { store_field(self.slot_address(index), value) }
+    }
+}
+pub struct HObject(UnsafeCell<()>);
+
+impl HValTrait for HObject {
+    const TAG: HeapTag = HeapTag::Object;
+}
+
+impl HObject {
+    pub fn map_slot_s(addr: *mut u8) -> *mut *mut u8 {
+        return unsafe { addr.offset(Self::MAP_OFFSET) as *mut *mut _ };
+    }
+
+    pub fn map_s(addr: *mut u8) -> *mut u8 {
+        return unsafe { load_field(Self::map_slot_s(addr)) };
+    }
+
+    pub fn set_map_s(addr: *mut u8, map: *mut u8) {
+        unsafe { store_field(Self::map_slot_s(addr), map) }
+    }
+
+    pub fn map(&self) -> *mut u8 {
+        Self::map_s(self.addr())
+    }
+
+    pub fn map_slot(&self) -> *mut *mut u8 {
+        Self::map_slot_s(self.addr())
+    }
+
+    pub fn proto_slot_s(addr: *mut u8) -> *mut *mut u8 {
+        return unsafe { addr.offset(Self::PROTO_OFFSET) as *mut *mut _ };
+    }
+
+    pub fn proto_s(addr: *mut u8) -> *mut u8 {
+        unsafe { load_field(Self::proto_slot_s(addr)) }
+    }
+
+    /// Sets the prototype of `addr`. Returns false and leaves it unchanged
+    /// if `addr` is on the prototype chain of `proto`, so chains never
+    /// become cycles.
+    pub fn set_proto_s(addr: *mut u8, proto: *mut u8) -> bool {
+        if matches!(HValue::get_tag(proto), HeapTag::Object | HeapTag::Array)
+            && Self::chain(proto).any(|obj| obj == addr)
+        {
+            return false;
+        }
+        Self::store_proto(addr, proto);
+        true
+    }
+
+    fn store_proto(addr: *mut u8, proto: *mut u8) {
+        unsafe { store_field(Self::proto_slot_s(addr), proto) }
+    }
+
+    pub fn proto(&self) -> *mut u8 {
+        Self::proto_s(self.addr())
+    }
+
+    pub fn proto_slot(&self) -> *mut *mut u8 {
+        Self::proto_slot_s(self.addr())
+    }
+
+    /// Allocates an empty object with prototype `proto`, nil for none.
+    pub fn new(gc: &mut CopyGC, proto: *mut u8) -> *mut u8 {
+        Self::new_with_slots(gc, proto, Self::DEFAULT_IN_OBJECT_SLOTS)
+    }
+
+    /// Allocates an empty object with room for `slots` properties before it
+    /// turns into a dictionary.
+    pub fn new_with_slots(gc: &mut CopyGC, proto: *mut u8, slots: u32) -> *mut u8 {
+        let slots = std::cmp::min(slots, Self::MAX_IN_OBJECT_SLOTS);
+        let mut live = [proto, gc.root_shape()];
+        let obj = gc
+            .alloc_tagged_keep(
+                HeapTag::Object,
+                (3 + slots as usize) * FIELD_SIZE,
+                &mut live,
+            )
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *obj.offset(HValue::REPR_OFF) = slots as u8;
+        }
+        Self::store_proto(obj, live[0]);
+        Self::set_map_s(obj, live[1]);
+        for i in 0..slots {
+            Self::set_slot_s(obj, i, HeapTag::Nil as u8 as *mut u8);
+        }
+        obj
+    }
+
+    /// Number of property slots stored in the object itself.
+    pub fn in_object_slots(addr: *mut u8) -> u32 {
+        if HValue::get_tag(addr) != HeapTag::Object {
+            return 0;
+        }
+        HValue::get_repr(addr) as u32
+    }
+
+    pub fn slot_address_s(addr: *mut u8, index: u32) -> *mut *mut u8 {
+        debug_assert!(index < Self::in_object_slots(addr));
+        unsafe {
+            addr.offset(Self::SLOTS_OFFSET + index as isize * FIELD_SIZE as isize) as *mut *mut u8
+        }
+    }
+
+    pub fn slot_s(addr: *mut u8, index: u32) -> *mut u8 {
+        unsafe { load_field(Self::slot_address_s(addr, index)) }
+    }
+
+    pub fn set_slot_s(addr: *mut u8, index: u32, value: *mut u8) {
+        unsafe { store_field(Self::slot_address_s(addr, index), value) }
+    }
+
+    fn is_nil(value: *mut u8) -> bool {
+        value == HeapTag::Nil as u8 as *mut u8
+    }
+
+    /// Shape of an object in fast mode, `None` for dictionaries. Inline
+    /// caches compare shapes and load `slot_s(addr, index)` directly.
+    pub fn shape_s(addr: *mut u8) -> Option<*mut HShape> {
+        let map = Self::map_s(addr);
+        if HValue::is_heap_pointer(map) && HValue::get_tag(map) == HeapTag::Shape {
+            return Some(map as *mut HShape);
+        }
+        None
+    }
+
+    /// Whether properties are kept in an `HMap` instead of in-object slots.
+    pub fn is_dictionary(addr: *mut u8) -> bool {
+        Self::shape_s(addr).is_none()
+    }
+
+    /// Dictionary of a dictionary mode object, only valid until the next
+    /// allocation.
+    fn own_map(addr: *mut u8) -> Option<*mut HMap> {
+        let map = Self::map_s(addr);
+        if Self::is_nil(map) || HValue::get_tag(map) != HeapTag::Map {
+            return None;
+        }
+        Some(map as *mut HMap)
+    }
+
+    /// Objects whose properties are looked up next, `addr` first and then
+    /// its prototype chain.
+    fn chain(addr: *mut u8) -> impl Iterator<Item = *mut u8> {
+        std::iter::successors(Some(addr), |&obj| {
+            let proto = Self::proto_s(obj);
+            match HValue::get_tag(proto) {
+                HeapTag::Object | HeapTag::Array => Some(proto),
+                _ => None,
+            }
+        })
+    }
+
+    /// Keys of the own properties, in insertion order for fast objects.
+    pub fn own_keys(addr: *mut u8) -> Vec<*mut u8> {
+        if let Some(shape) = Self::shape_s(addr) {
+            let mut keys = unsafe { (*shape).keys() };
+            keys.reverse();
+            return keys;
+        }
+        Self::own_map(addr).map_or_else(Vec::new, |map| unsafe {
+            (*map).iter().map(|(key, _)| key).collect()
+        })
+    }
+
+    pub fn get_own_property(addr: *mut u8, key: *mut u8) -> Option<*mut u8> {
+        if let Some(shape) = Self::shape_s(addr) {
+            let index = unsafe { (*shape).lookup(key) };
+            return index.map(|index| Self::slot_s(addr, index));
+        }
+        Self::own_map(addr).and_then(|map| unsafe { (*map).get(key) })
+    }
+
+    /// `LoadProperty`: the value of `key` on `addr` or the closest
+    /// prototype that has it, nil if there is none.
+    pub fn get_property(addr: *mut u8, key: *mut u8) -> *mut u8 {
+        Self::chain(addr)
+            .find_map(|obj| Self::get_own_property(obj, key))
+            .unwrap_or(HeapTag::Nil as u8 as *mut u8)
+    }
+
+    pub fn has_own_property(addr: *mut u8, key: *mut u8) -> bool {
+        if let Some(shape) = Self::shape_s(addr) {
+            return unsafe { (*shape).lookup(key).is_some() };
+        }
+        Self::own_map(addr).is_some_and(|map| unsafe { (*map).contains(key) })
+    }
+
+    pub fn has_property(addr: *mut u8, key: *mut u8) -> bool {
+        Self::chain(addr).any(|obj| Self::has_own_property(obj, key))
+    }
+
+    /// `StoreProperty`: stores always go to `addr` itself and shadow
+    /// properties of prototypes. New properties move a fast object along
+    /// its shape's transitions while there are free in-object slots, after
+    /// that it becomes a dictionary. Returns the object, which may have
+    /// been moved by a collection, or an exception if the object flags
+    /// forbid the store.
+    pub fn set_property(
+        gc: &mut CopyGC,
+        addr: *mut u8,
+        key: *mut u8,
+        value: *mut u8,
+    ) -> Result<*mut u8, *mut u8> {
+        if Self::flags(addr) != 0 {
+            let message = if !Self::has_own_property(addr, key) {
+                Some("cannot add a property to a non-extensible object")
+            } else if Self::is_frozen(addr) {
+                Some("cannot change a property of a frozen object")
+            } else {
+                None
+            };
+            if let Some(message) = message {
+                return Err(HString::from_str(gc, message));
+            }
+        }
+        Ok(Self::store_property(gc, addr, key, value))
+    }
+
+    /// `set_property` without checking the object flags.
+    fn store_property(gc: &mut CopyGC, addr: *mut u8, key: *mut u8, value: *mut u8) -> *mut u8 {
+        if let Some(shape) = Self::shape_s(addr) {
+            let (index, count) = unsafe { ((*shape).lookup(key), (*shape).count()) };
+            if let Some(index) = index {
+                Self::set_slot_s(addr, index, value);
+                return addr;
+            }
+            if count < Self::in_object_slots(addr) {
+                let mut live = [addr, value];
+                for value in live.iter_mut() {
+                    gc.add_root(value, RefType::Persistent);
+                }
+                let key = if HValue::get_tag(key) == HeapTag::String {
+                    gc.intern(key)
+                } else {
+                    key
+                };
+                let next = HShape::transition(gc, Self::map_s(live[0]), key);
+                for value in live.iter_mut() {
+                    gc.remove_root(value);
+                }
+                let [obj, value] = live;
+                let index = unsafe { (*(next as *mut HShape)).count() } - 1;
+                Self::set_slot_s(obj, index, value);
+                Self::set_map_s(obj, next);
+                return obj;
+            }
+        }
+
+        let mut live = [addr, key, value];
+        if Self::own_map(addr).is_none() {
+            Self::normalize(gc, &mut live);
+        }
+
+        let [mut obj, key, value] = live;
+        gc.add_root(&mut obj, RefType::Persistent);
+        let map = HMap::set(gc, Self::map_s(obj), key, value);
+        gc.remove_root(&mut obj);
+        Self::set_map_s(obj, map);
+        obj
+    }
+
+    /// Object flags, a combination of `NON_EXTENSIBLE_BIT`, `SEALED_BIT`
+    /// and `FROZEN_BIT`. Arrays use the same flags for their elements.
+    pub fn flags(addr: *mut u8) -> u32 {
+        unsafe { *(addr.offset(Self::MASK_OFFSET) as *mut u32) }
+    }
+
+    fn set_flags(addr: *mut u8, flags: u32) {
+        unsafe {
+            *(addr.offset(Self::MASK_OFFSET) as *mut u32) = flags;
+        }
+    }
+
+    /// No properties can be added from now on. Like sealing and freezing,
+    /// this cannot be undone.
+    pub fn prevent_extensions(addr: *mut u8) {
+        Self::set_flags(addr, Self::flags(addr) | Self::NON_EXTENSIBLE_BIT);
+    }
+
+    /// No properties can be added or deleted from now on.
+    pub fn seal(addr: *mut u8) {
+        Self::set_flags(
+            addr,
+            Self::flags(addr) | Self::NON_EXTENSIBLE_BIT | Self::SEALED_BIT,
+        );
+    }
+
+    /// No properties can be added, deleted or changed from now on.
+    pub fn freeze(addr: *mut u8) {
+        Self::set_flags(
+            addr,
+            Self::NON_EXTENSIBLE_BIT | Self::SEALED_BIT | Self::FROZEN_BIT,
+        );
+    }
+
+    pub fn is_extensible(addr: *mut u8) -> bool {
+        Self::flags(addr) & Self::NON_EXTENSIBLE_BIT == 0
+    }
+
+    pub fn is_sealed(addr: *mut u8) -> bool {
+        Self::flags(addr) & Self::SEALED_BIT != 0
+    }
+
+    pub fn is_frozen(addr: *mut u8) -> bool {
+        Self::flags(addr) & Self::FROZEN_BIT != 0
+    }
+
+    /// Moves the properties of the fast object `live[0]` into a new `HMap`,
+    /// the other values in `live` are kept alive.
+    fn normalize(gc: &mut CopyGC, live: &mut [*mut u8]) {
+        let count = Self::shape_s(live[0]).map_or(0, |shape| unsafe { (*shape).count() });
+        let mut map = HMap::new_keep(gc, count * 2, live);
+        let obj = live[0];
+        if let Some(shape) = Self::shape_s(obj) {
+            let mut keys = unsafe { (*shape).keys() };
+            keys.reverse();
+            for (index, &key) in keys.iter().enumerate() {
+                // keys are interned and the map is big enough, so this
+                // never allocates
+                map = HMap::set(gc, map, key, Self::slot_s(obj, index as u32));
+            }
+            for i in 0..Self::in_object_slots(obj) {
+                Self::set_slot_s(obj, i, HeapTag::Nil as u8 as *mut u8);
+            }
+        }
+        Self::set_map_s(live[0], map);
+    }
+
+    /// Copy of the object or array `addr` with its own dictionary and
+    /// elements, property values and elements are shared. Shapes are
+    /// immutable and shared as well. The copy is extensible, even if `addr`
+    /// is not.
+    pub fn copy(gc: &mut CopyGC, addr: *mut u8) -> *mut u8 {
+        let mut obj = HValue::shallow_copy(gc, &mut [addr]);
+        Self::set_flags(obj, 0);
+        if Self::own_map(obj).is_some() {
+            let mut live = [Self::map_s(obj), obj];
+            let map = HValue::shallow_copy(gc, &mut live);
+            obj = live[1];
+            Self::set_map_s(obj, map);
+        }
+        if HValue::get_tag(obj) == HeapTag::Array {
+            let mut live = [HArray::elements(obj), obj];
+            let elements = HValue::shallow_copy(gc, &mut live);
+            obj = live[1];
+            HArray::set_elements(obj, elements);
+        }
+        obj
+    }
+
+    /// `DeleteProperty`: removes an own property, prototypes are not
+    /// touched. Fast objects become dictionaries. Returns whether there was
+    /// a property, and the object, which may have been moved. Deleting a
+    /// property of a sealed object throws.
+    pub fn delete_property(
+        gc: &mut CopyGC,
+        addr: *mut u8,
+        key: *mut u8,
+    ) -> Result<(bool, *mut u8), *mut u8> {
+        if !Self::has_own_property(addr, key) {
+            return Ok((false, addr));
+        }
+        if Self::is_sealed(addr) {
+            let message = "cannot delete a property of a sealed object";
+            return Err(HString::from_str(gc, message));
+        }
+        let mut live = [addr];
+        if !Self::is_dictionary(addr) {
+            Self::normalize(gc, &mut live);
+        }
+        let deleted = Self::own_map(live[0]).is_some_and(|map| unsafe { (*map).delete(key) });
+        Ok((deleted, live[0]))
+    }
+
+    pub const MASK_OFFSET: isize = interior_offset(1);
+    pub const NON_EXTENSIBLE_BIT: u32 = 0x01;
+    pub const SEALED_BIT: u32 = 0x02;
+    pub const FROZEN_BIT: u32 = 0x04;
+    pub const MAP_OFFSET: isize = interior_offset(2);
+    pub const PROTO_OFFSET: isize = interior_offset(3);
+    pub const SLOTS_OFFSET: isize = interior_offset(4);
+    /// In-object slots of objects created by `HObject::new`.
+    pub const DEFAULT_IN_OBJECT_SLOTS: u32 = 4;
+    /// Objects never have more in-object slots than this, objects with
+    /// more properties are dictionaries.
+    pub const MAX_IN_OBJECT_SLOTS: u32 = 32;
+}
+pub struct HShape(UnsafeCell<()>);
+
+impl HValTrait for HShape {
+    const TAG: HeapTag = HeapTag::Shape;
+}
+
+/// Hidden class of fast mode objects. A shape adds one property `key` to
+/// its parent shape, stored in the object's in-object slot `count - 1`.
+/// Objects that get the same properties in the same order share shapes,
+/// which are found through the `transitions` map of the parent.
+impl HShape {
+    pub const PARENT_OFFSET: isize = interior_offset(1);
+    pub const KEY_OFFSET: isize = interior_offset(2);
+    pub const TRANSITIONS_OFFSET: isize = interior_offset(3);
+    pub const COUNT_OFFSET: isize = interior_offset(4);
+    pub const SIZE: usize = 4 * FIELD_SIZE;
+
+    /// Allocates a shape without properties, see `CopyGC::root_shape`.
+    pub fn new_root(gc: &mut CopyGC) -> *mut u8 {
+        Self::new_keep(gc, 0, &mut [])
+    }
+
+    fn new_keep(gc: &mut CopyGC, count: u32, live: &mut [*mut u8]) -> *mut u8 {
+        let addr = gc
+            .alloc_tagged_keep(HeapTag::Shape, Self::SIZE, live)
+            .to_mut_ptr::<u8>();
+        let shape: &HShape = unsafe { &*(addr as *mut HShape) };
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        unsafe {
+            store_field(shape.parent_slot(), nil);
+            store_field(shape.key_slot(), nil);
+            store_field(shape.transitions_slot(), nil);
+            *(addr.offset(Self::COUNT_OFFSET) as *mut u32) = count;
+        }
+        addr
+    }
+
+    pub fn parent_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::PARENT_OFFSET) as *mut *mut u8 }
+    }
+
+    pub fn parent(&self) -> *mut u8 {
+        unsafe { load_field(self.parent_slot()) }
+    }
+
+    pub fn key_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::KEY_OFFSET) as *mut *mut u8 }
+    }
+
+    pub fn key(&self) -> *mut u8 {
+        unsafe { load_field(self.key_slot()) }
+    }
+
+    pub fn transitions_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::TRANSITIONS_OFFSET) as *mut *mut u8 }
+    }
+
+    pub fn transitions(&self) -> *mut u8 {
+        unsafe { load_field(self.transitions_slot()) }
+    }
+
+    /// Number of properties described by this shape and its parents.
+    pub fn count(&self) -> u32 {
+        unsafe { *(self.addr().offset(Self::COUNT_OFFSET) as *mut u32) }
+    }
+
+    /// In-object slot index of `key`.
+    pub fn lookup(&self, key: *mut u8) -> Option<u32> {
+        let mut shape = self;
+        while shape.count() > 0 {
+            if HMap::keys_equal(shape.key(), key) {
+                return Some(shape.count() - 1);
+            }
+            shape = unsafe { &*(shape.parent() as *mut HShape) };
+        }
+        None
+    }
+
+    /// Keys of all properties, the most recently added first.
+    pub fn keys(&self) -> Vec<*mut u8> {
+        let mut keys = Vec::with_capacity(self.count() as usize);
+        let mut shape = self;
+        while shape.count() > 0 {
+            keys.push(shape.key());
+            shape = unsafe { &*(shape.parent() as *mut HShape) };
+        }
+        keys
+    }
+
+    /// Shape of objects with the properties of `shape` and `key`, created
+    /// and recorded in the transitions of `shape` the first time.
+    pub fn transition(gc: &mut CopyGC, shape: *mut u8, key: *mut u8) -> *mut u8 {
+        let transitions = unsafe { (*(shape as *mut HShape)).transitions() };
+        if HValue::is_heap_pointer(transitions) {
+            if let Some(next) = unsafe { (*(transitions as *mut HMap)).get(key) } {
+                return next;
+            }
+        }
+
+        let count = unsafe { (*(shape as *mut HShape)).count() } + 1;
+        let mut live = [shape, key];
+        let next = Self::new_keep(gc, count, &mut live);
+        let [shape, key] = live;
+        unsafe {
+            let new: &HShape = &*(next as *mut HShape);
+            store_field(new.parent_slot(), shape);
+            store_field(new.key_slot(), key);
+        }
+
+        let mut live = [shape, key, next];
+        for value in live.iter_mut() {
+            gc.add_root(value, RefType::Persistent);
+        }
+        let mut transitions = unsafe { (*(live[0] as *mut HShape)).transitions() };
+        if !HValue::is_heap_pointer(transitions) {
+            transitions = HMap::new(gc, 0);
+        }
+        let transitions = HMap::set(gc, transitions, live[1], live[2]);
+        for value in live.iter_mut() {
+            gc.remove_root(value);
+        }
+        let [shape, _, next] = live;
+        unsafe { store_field((*(shape as *mut HShape)).transitions_slot(), transitions) };
+        next
+    }
+}
+/// Opaque data owned by the embedder.
+pub struct HExternData(UnsafeCell<()>);
+
+impl HValTrait for HExternData {
+    const TAG: HeapTag = HeapTag::ExternData;
+}
+
+pub struct HFunction(UnsafeCell<()>);
+
+impl HValTrait for HFunction {
+    const TAG: HeapTag = HeapTag::Function;
+}
+
+/// Entry point of compiled script code. `args` points to `argc` values, the
+/// first `HFunction::argc` of them are also in the slots of `context`. The
+/// function, `context`, `receiver` and `args` are rooted until the code
+/// returns, and `args` is updated when collections move them. Code that
+/// throws stores the thrown value in `exception` and returns `HOLE`.
+pub type CompiledCode = unsafe extern "C" fn(
+    gc: *mut CopyGC,
+    context: *mut u8,
+    receiver: *mut u8,
+    args: *const *mut u8,
+    argc: u32,
+    exception: *mut *mut u8,
+) -> *mut u8;
+
+/// Function implemented in Rust. It returns the result or the thrown value.
+/// The values it receives are not rooted, anything that may allocate can
+/// move them.
+pub type NativeFunction =
+    fn(gc: &mut CopyGC, receiver: *mut u8, args: &[*mut u8]) -> Result<*mut u8, *mut u8>;
+
+/// Script functions run compiled code in a fresh context whose parent is
+/// the captured context, or the root context for functions created with
+/// `BINDING_CONTEXT_TAG` as parent. Native functions call a Rust function
+/// pointer stored in the code field.
+impl HFunction {
+    const NATIVE_BIT: u8 = 0x01;
+
+    /// Allocates a script function running `code`, closing over `parent`.
+    pub fn new(
+        gc: &mut CopyGC,
+        code: CompiledCode,
+        parent: *mut u8,
+        root: *mut u8,
+        argc: u32,
+    ) -> *mut u8 {
+        Self::allocate(gc, code as *mut u8, parent, root, argc, 0)
+    }
+
+    /// Allocates a function calling `native`, which gets at least `argc`
+    /// arguments.
+    pub fn new_native(gc: &mut CopyGC, native: NativeFunction, argc: u32) -> *mut u8 {
+        Self::allocate(gc, native as *mut u8, NIL, NIL, argc, Self::NATIVE_BIT)
+    }
+
+    fn allocate(
+        gc: &mut CopyGC,
+        code: *mut u8,
+        parent: *mut u8,
+        root: *mut u8,
+        argc: u32,
+        repr: u8,
+    ) -> *mut u8 {
+        let mut live = [parent, root];
+        let addr = gc
+            .alloc_tagged_keep(
+                HeapTag::Function,
+                (3 + RAW_FIELDS as usize) * FIELD_SIZE,
+                &mut live,
+            )
+            .to_mut_ptr::<u8>();
+        unsafe {
+            *addr.offset(HValue::REPR_OFF) = repr;
+            let fun: &HFunction = &*(addr as *mut HFunction);
+            fun.set_parent(live[0]);
+            fun.set_root(live[1]);
+            fun.set_code(code);
+            *fun.argc_off() = argc;
+        }
+        addr
+    }
+
+    pub fn is_native(&self) -> bool {
+        HValue::get_repr(self.addr()) & Self::NATIVE_BIT != 0
+    }
+
+    /// Calls `function` with `receiver` as `this`. Passing fewer than `argc`
+    /// arguments throws, extra arguments are only visible through `args`.
+    pub fn call(
+        gc: &mut CopyGC,
+        function: *mut u8,
+        receiver: *mut u8,
+        args: &[*mut u8],
+    ) -> Result<*mut u8, *mut u8> {
+        if HValue::get_tag(function) != HeapTag::Function {
+            return Err(HString::from_str(gc, "value is not a function"));
+        }
+        let fun: &HFunction = unsafe { &*(function as *mut HFunction) };
+        let argc = fun.argc();
+        if (args.len() as u32) < argc {
+            let message = format!("function expects {} arguments, got {}", argc, args.len());
+            return Err(HString::from_str(gc, &message));
+        }
+
+        if fun.is_native() {
+            let native: NativeFunction = unsafe { std::mem::transmute(fun.code()) };
+            return native(gc, receiver, args);
+        }
+
+        // everything has to survive allocating the context, and stay rooted
+        // while the code runs, which reads the arguments from `live`
+        let mut live: Vec<*mut u8> = Vec::with_capacity(args.len() + 2);
+        live.push(function);
+        live.push(receiver);
+        live.extend_from_slice(args);
+        for value in live.iter_mut() {
+            gc.add_root(value, RefType::Persistent);
+        }
+        let parent = if fun.parent() == BINDING_CONTEXT_TAG as *mut u8 {
+            fun.root()
+        } else {
+            fun.parent()
+        };
+        let mut context = HContext::new(gc, parent, argc);
+        gc.add_root(&mut context, RefType::Persistent);
+
+        let fun: &HFunction = unsafe { &*(live[0] as *mut HFunction) };
+        let ctx: &HContext = unsafe { &*(context as *mut HContext) };
+        for i in 0..argc {
+            ctx.set_slot(i, live[2 + i as usize]);
+        }
+        let code: CompiledCode = unsafe { std::mem::transmute(fun.code()) };
+        let mut exception = HOLE;
+        let result = unsafe {
+            code(
+                gc,
+                context,
+                live[1],
+                live[2..].as_ptr(),
+                args.len() as u32,
+                &mut exception,
+            )
+        };
+        gc.remove_root(&mut context);
+        for value in live.iter_mut() {
+            gc.remove_root(value);
+        }
+        if exception != HOLE {
+            Err(exception)
+        } else {
+            Ok(result)
+        }
+    }
+
+    pub const PARENT_OFFSET: isize = interior_offset(1);
+    /// Code is a raw pointer, it is never compressed.
+    pub const CODE_OFFSET: isize = interior_offset(2);
+    pub const ROOT_OFFSET: isize = interior_offset(2 + RAW_FIELDS);
+    pub const ARGC_OFFSET: isize = interior_offset(3 + RAW_FIELDS);
+
+    pub fn root_s(addr: *mut u8) -> *mut u8 {
+        unsafe { load_field(addr.offset(Self::ROOT_OFFSET) as *mut *mut u8) }
+    }
+
+    pub fn root_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::ROOT_OFFSET) as *mut *mut u8 }
+    }
+
+    pub fn root(&self) -> *mut u8 {
+        unsafe { load_field(self.root_slot()) }
+    }
+
+    pub fn set_root(&self, root: *mut u8) {
+        unsafe { store_field(self.root_slot(), root) }
+    }
+
+    pub fn argc(&self) -> u32 {
+        unsafe { *self.argc_off() }
+    }
+
+    pub fn parent(&self) -> *mut u8 {
+        unsafe { load_field(self.parent_slot()) }
+    }
+
+    pub fn set_parent(&self, parent: *mut u8) {
+        unsafe { store_field(self.parent_slot(), parent) }
+    }
+
+    pub fn parent_slot(&self) -> *mut *mut u8 {
+        unsafe { self.addr().offset(Self::PARENT_OFFSET) as *mut *mut _ }
+    }
+
+    pub fn code(&self) -> *mut u8 {
+        unsafe { std::ptr::read_unaligned(self.addr().offset(Self::CODE_OFFSET) as *mut *mut u8) }
+    }
+
+    pub fn set_code(&self, code: *mut u8) {
+        unsafe {
+            std::ptr::write_unaligned(self.addr().offset(Self::CODE_OFFSET) as *mut *mut u8, code)
+        }
+    }
+
+    pub fn argc_off(&self) -> *mut u32 {
+        unsafe { self.addr().offset(Self::ARGC_OFFSET) as *mut u32 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_string_concat() {
+        let mut gc = CopyGC::new();
+        let short = HString::from_str(&mut gc, "foo");
+        let flat = HString::concat(&mut gc, short, short);
+        assert!(!HString::is_cons(flat));
+        assert_eq!(string(flat), "foofoo");
+
+        let long = HString::from_str(&mut gc, "a rather long string");
+        let cons = HString::concat(&mut gc, long, flat);
+        assert!(HString::is_cons(cons));
+        assert_eq!(HString::static_length(cons), 26);
+        assert_eq!(string(cons), "a rather long stringfoofoo");
+
+        let empty = HString::from_str(&mut gc, "");
+        assert_eq!(HString::concat(&mut gc, empty, long), long);
+    }
+
+    #[test]
+    fn test_string_hash_and_compare() {
+        let mut gc = CopyGC::new();
+        let left = HString::from_str(&mut gc, "hello, hello, hello, ");
+        let right = HString::from_str(&mut gc, "world");
+        let cons = HString::concat(&mut gc, left, right);
+        let flat = HString::from_str(&mut gc, "hello, hello, hello, world");
+
+        unsafe {
+            assert_eq!(
+                (*(cons as *mut HString)).hash(),
+                (*(flat as *mut HString)).hash()
+            );
+        }
+        assert!(HString::equals(cons, flat));
+        assert!(!HString::equals(left, right));
+        assert_eq!(HString::compare(left, right), Ordering::Less);
+        assert_eq!(HString::compare(cons, flat), Ordering::Equal);
+
+        let flattened = HString::flatten(&mut gc, cons);
+        assert!(!HString::is_cons(flattened));
+        assert_eq!(string(flattened), "hello, hello, hello, world");
+        assert_eq!(HString::flatten(&mut gc, cons), flattened);
+    }
+
+    #[test]
+    fn test_string_code_points() {
+        let mut gc = CopyGC::new();
+        let string = HString::from_str(&mut gc, "naïve café ☕");
+        let value = unsafe { &*(string as *mut HString) };
+        assert_eq!(value.length(), 16);
+        assert_eq!(value.char_length(), 12);
+        assert!(!value.is_ascii());
+        assert_eq!(value.char_at(2), Some('ï'));
+        assert_eq!(value.char_at(11), Some('☕'));
+        assert_eq!(value.char_at(12), None);
+        assert!(HString::from_utf8(&mut gc, &[0x66, 0xff]).is_err());
+
+        let cons = HString::concat(&mut gc, string, string);
+        assert_eq!(HString::static_char_length(cons), 24);
+    }
+
+    #[test]
+    fn test_substring() {
+        let mut gc = CopyGC::new();
+        let source = HString::from_str(&mut gc, "Grüße aus der schönen Stadt Köln");
+        let short = HString::substring(&mut gc, source, 0, 5);
+        assert_eq!(HString::static_repr(short), StrRepr::Normal);
+        assert_eq!(string(short), "Grüße");
+
+        let mut sliced = HString::substring(&mut gc, source, 6, 100);
+        assert_eq!(HString::static_repr(sliced), StrRepr::Sliced);
+        assert_eq!(string(sliced), "aus der schönen Stadt Köln");
+        assert_eq!(HString::static_char_length(sliced), 26);
+
+        // slices of slices share the flat parent
+        let nested = HString::substring(&mut gc, sliced, 8, 26);
+        assert_eq!(string(nested), "schönen Stadt Köln");
+        unsafe {
+            assert_eq!((*(nested as *mut HString)).parent(), source);
+        }
+
+        // the parent is only kept alive by the slice
+        gc.add_root(&mut sliced, RefType::Persistent);
+        gc.collect_garbage();
+        assert_eq!(string(sliced), "aus der schönen Stadt Köln");
+        let parent = unsafe { (*(sliced as *mut HString)).parent() };
+        assert_eq!(string(parent), "Grüße aus der schönen Stadt Köln");
+    }
+
+    #[test]
+    fn test_case_conversion() {
+        let mut gc = CopyGC::new();
+        let source = HString::from_str(&mut gc, "Straße İstanbul ÆØÅ");
+        let upper = HString::to_upper_case(&mut gc, source);
+        assert_eq!(string(upper), "STRASSE İSTANBUL ÆØÅ");
+        let lower = HString::to_lower_case(&mut gc, upper);
+        assert_eq!(string(lower), "strasse i̇stanbul æøå");
+        assert_eq!(HString::to_lower_case(&mut gc, lower), lower);
+    }
+
+    #[test]
+    #[cfg(feature = "nan-boxing")]
+    fn test_nan_boxing() {
+        let mut gc = CopyGC::new();
+        for &value in &[0.5, -1.25, 1e300, -0.0, f64::INFINITY] {
+            let boxed = HNumber::from_double(value);
+            assert!(HNumber::is_double(boxed));
+            assert!(!HValue::is_heap_pointer(boxed));
+            assert_eq!(HValue::get_tag(boxed), HeapTag::Number);
+            assert_eq!(HNumber::to_double(boxed).to_bits(), value.to_bits());
+        }
+        assert!(HNumber::to_double(HNumber::from_double(-f64::NAN)).is_nan());
+
+        let int = HNumber::from_double(-7.0);
+        assert!(HNumber::is_int(int));
+        assert_eq!(int, HNumber::tag(-7) as *mut u8);
+        assert_eq!(HNumber::untag(int), -7);
+
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        assert!(!HValue::is_unboxed(nil));
+        let boxed = HString::from_str(&mut gc, "boxed");
+        assert!(HValue::is_heap_pointer(boxed));
+
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, HNumber::from_double(2.5), boxed);
+        map = HMap::set(&mut gc, map, HNumber::from_double(3.0), nil);
+        gc.add_root(&mut map, RefType::Persistent);
+        gc.collect_garbage();
+        let table = unsafe { &*(map as *mut HMap) };
+        assert_eq!(
+            string(table.get(HNumber::from_double(2.5)).unwrap()),
+            "boxed"
+        );
+        assert_eq!(table.get(HNumber::tag(3) as *mut u8), Some(nil));
+    }
+
+    #[test]
+    fn test_immediates() {
+        let mut gc = CopyGC::new();
+        assert_eq!(HBoolean::new(1 < 2), TRUE);
+        assert!(!HBoolean::value(HBoolean::new(false)));
+        for &(value, tag) in &[
+            (NIL, HeapTag::Nil),
+            (UNDEFINED, HeapTag::Undefined),
+            (HOLE, HeapTag::Undefined),
+            (TRUE, HeapTag::Boolean),
+            (FALSE, HeapTag::Boolean),
+        ] {
+            assert!(HValue::is_immediate(value));
+            assert!(!HValue::is_heap_pointer(value));
+            assert!(!HValue::is_unboxed(value));
+            assert_eq!(HValue::get_tag(value), tag);
+        }
+        assert!(!HValue::is_immediate(int(3)));
+
+        // immediates are plain keys and values, nothing to rehash or trace
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, TRUE, UNDEFINED);
+        map = HMap::set(&mut gc, map, FALSE, NIL);
+        assert!(!HMap::has_identity_keys(map));
+        gc.add_root(&mut map, RefType::Persistent);
+        gc.collect_garbage();
+        let table = unsafe { &*(map as *mut HMap) };
+        assert_eq!(table.get(TRUE), Some(UNDEFINED));
+        assert_eq!(table.get(FALSE), Some(NIL));
+    }
+
+    #[test]
+    fn test_number_arithmetic() {
+        let mut gc = CopyGC::new();
+        let sum = HNumber::binop(&mut gc, NumberOp::Add, int(40), int(2));
+        assert_eq!(sum, int(42));
+        let third = HNumber::binop(&mut gc, NumberOp::Div, int(1), int(3));
+        assert!(!HNumber::is_int(third));
+        assert_eq!(HNumber::to_double(third), 1.0 / 3.0);
+        assert_eq!(
+            HNumber::binop(&mut gc, NumberOp::Div, int(9), int(3)),
+            int(3)
+        );
+        assert_eq!(
+            HNumber::binop(&mut gc, NumberOp::Mod, int(-7), int(3)),
+            int(-1)
+        );
+
+        let zero = HNumber::binop(&mut gc, NumberOp::Mul, int(0), int(-5));
+        assert!(HNumber::to_double(zero).is_sign_negative());
+        let infinity = HNumber::binop(&mut gc, NumberOp::Div, int(1), int(0));
+        assert_eq!(HNumber::to_double(infinity), f64::INFINITY);
+
+        let mut big = HNumber::binop(&mut gc, NumberOp::Add, int(MAX_SMALL_INT), int(1));
+        assert!(!HNumber::is_int(big));
+        assert_eq!(HNumber::to_double(big), MAX_SMALL_INT as f64 + 1.0);
+        gc.add_root(&mut big, RefType::Persistent);
+        gc.collect_garbage();
+        assert_eq!(HNumber::to_double(big), MAX_SMALL_INT as f64 + 1.0);
+
+        // results that fit again become small integers
+        let back = HNumber::binop(&mut gc, NumberOp::Div, big, int(2));
+        assert_eq!(back, int(MAX_SMALL_INT / 2 + 1));
+        assert_eq!(
+            HNumber::from_i64(&mut gc, MIN_SMALL_INT),
+            int(MIN_SMALL_INT)
+        );
+        assert_eq!(HNumber::compare(big, back), Some(Ordering::Greater));
+        assert_eq!(HNumber::compare(int(1), third), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn test_number_formatting() {
+        let cases = [
+            (1.5, "1.5"),
+            (-0.0, "0"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (123456789.0, "123456789"),
+            (1e21, "1e+21"),
+            (1.25e-7, "1.25e-7"),
+            (0.000001, "0.000001"),
+            (-2.5e100, "-2.5e+100"),
+            (f64::NAN, "NaN"),
+            (f64::NEG_INFINITY, "-Infinity"),
+        ];
+        for &(value, text) in cases.iter() {
+            assert_eq!(HNumber::format_double(value), text);
+        }
+        assert_eq!(HNumber::format_int(-255, 16), "-ff");
+        assert_eq!(HNumber::format_int(i64::MIN, 2).len(), 65);
+
+        let mut gc = CopyGC::new();
+        assert_eq!(HNumber::to_string_radix(int(35), 36), "z");
+        let double = HNumber::new(&mut gc, 2f64.powi(70));
+        assert_eq!(HNumber::to_string_radix(double, 16), "400000000000000000");
+        let double = HNumber::new(&mut gc, -0.5);
+        assert_eq!(HNumber::to_string_radix(double, 2), "-0.5");
+
+        assert_eq!(HNumber::parse(&mut gc, "42"), Some(int(42)));
+        assert_eq!(HNumber::parse(&mut gc, "-0x1F"), Some(int(-31)));
+        assert_eq!(HNumber::parse(&mut gc, "1e3"), Some(int(1000)));
+        for &text in ["2.5", ".5e1", "-0", "1e400", "-Infinity"].iter() {
+            let value = HNumber::parse(&mut gc, text).unwrap();
+            let expected: f64 = text.replace("Infinity", "inf").parse().unwrap();
+            assert_eq!(HNumber::to_double(value).to_bits(), expected.to_bits());
+        }
+        for &text in ["", "0x", "1e", "1.2.3", "inf", "NaN", " 1", "--1", "0x1.5"].iter() {
+            assert_eq!(HNumber::parse(&mut gc, text), None, "{:?}", text);
+        }
+
+        let huge = "123456789012345678901234567890";
+        let value = HNumber::parse(&mut gc, huge).unwrap();
+        assert_eq!(HValue::get_tag(value), HeapTag::Number);
+        gc.set_strict_integers(true);
+        let value = HNumber::parse(&mut gc, huge).unwrap();
+        assert_eq!(HBigInt::to_string_radix(value, 10), huge);
+        assert_eq!(HNumber::to_string_radix(value, 10), huge);
+    }
+
+    #[test]
+    fn test_bigint() {
+        let mut gc = CopyGC::new();
+        let big = int(MAX_SMALL_INT);
+        let overflow = HNumber::binop(&mut gc, NumberOp::Mul, big, big);
+        assert!(!HBigInt::is_integer(overflow));
+
+        gc.set_strict_integers(true);
+        let mut square = HNumber::binop(&mut gc, NumberOp::Mul, big, big);
+        assert_eq!(HValue::get_tag(square), HeapTag::BigInt);
+        gc.add_root(&mut square, RefType::Persistent);
+        gc.collect_garbage();
+        let expected = MAX_SMALL_INT as i128 * MAX_SMALL_INT as i128;
+        assert_eq!(HBigInt::to_string_radix(square, 10), expected.to_string());
+        assert_eq!(
+            HBigInt::to_string_radix(square, 16),
+            format!("{:x}", expected)
+        );
+
+        // results that fit are small integers again
+        let root = HNumber::binop(&mut gc, NumberOp::Div, square, big);
+        assert_eq!(root, big);
+        assert_eq!(HNumber::binop(&mut gc, NumberOp::Mod, square, big), int(0));
+
+        let parsed = HBigInt::parse(&mut gc, &format!("-0x1{}", "0".repeat(24))).unwrap();
+        assert_eq!(
+            HBigInt::to_string_radix(parsed, 10),
+            "-79228162514264337593543950336"
+        );
+        assert_eq!(HBigInt::shr(&mut gc, parsed, 96), int(-1));
+        assert_eq!(HBigInt::shr(&mut gc, int(-3), 1), int(-2));
+        let shifted = HBigInt::shl(&mut gc, int(-1), 96);
+        assert_eq!(HBigInt::compare(shifted, parsed), Ordering::Equal);
+        assert!(HBigInt::parse(&mut gc, "12a").is_none());
+
+        assert_eq!(HBigInt::compare(parsed, square), Ordering::Less);
+        assert_eq!(HNumber::compare(square, int(1)), Some(Ordering::Greater));
+        let sum = HBigInt::add(&mut gc, square, parsed);
+        let back = HBigInt::sub(&mut gc, sum, parsed);
+        assert_eq!(HBigInt::compare(back, square), Ordering::Equal);
+        assert!(HBigInt::div(&mut gc, square, int(0)).is_none());
+
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, square, int(1));
+        assert_eq!(unsafe { (*(map as *mut HMap)).get(back) }, Some(int(1)));
+
+        // the smallest i128 and small integer bounds
+        let min = HBigInt::parse(&mut gc, "-0x80000000000000000000000000000000").unwrap();
+        assert_eq!(
+            HBigInt::to_string_radix(min, 16),
+            "-80000000000000000000000000000000"
+        );
+        let text = MIN_SMALL_INT.to_string();
+        assert_eq!(HBigInt::parse(&mut gc, &text), Some(int(MIN_SMALL_INT)));
+        let text = (MIN_SMALL_INT as i128 - 1).to_string();
+        let below = HBigInt::parse(&mut gc, &text).unwrap();
+        assert_eq!(HValue::get_tag(below), HeapTag::BigInt);
+        assert_eq!(HBigInt::to_string_radix(below, 10), text);
+    }
+
+    #[test]
+    fn test_context_chain() {
+        let mut gc = CopyGC::new();
+        let mut outer = HContext::new(&mut gc, NIL, 2);
+        gc.add_root(&mut outer, RefType::Persistent);
+        let middle = HContext::new(&mut gc, outer, 1);
+        let mut inner = HContext::new(&mut gc, middle, 3);
+        gc.add_root(&mut inner, RefType::Persistent);
+
+        let value = HString::from_str(&mut gc, "captured");
+        unsafe {
+            let ctx = &*(inner as *mut HContext);
+            ctx.store(2, 1, value);
+            ctx.store(0, 2, int(5));
+            assert_eq!(ctx.lookup(1, 0), NIL);
+            assert!(!ctx.ancestor(2).has_parent());
+        }
+
+        gc.collect_garbage();
+        unsafe {
+            let ctx = &*(inner as *mut HContext);
+            assert_eq!(ctx.ancestor(2).addr(), outer);
+            assert_eq!(string(ctx.lookup(2, 1)), "captured");
+            assert_eq!(ctx.lookup(0, 2), int(5));
+
+            let binding = &*(HContext::new_binding(&mut gc, 1) as *mut HContext);
+            assert!(binding.is_binding());
+            assert!(!binding.has_parent());
+            binding.bind(inner);
+            assert!(!binding.is_binding());
+            assert_eq!(binding.lookup(3, 1), ctx.lookup(2, 1));
+        }
+    }
+
+    fn native_add(
+        gc: &mut CopyGC,
+        _receiver: *mut u8,
+        args: &[*mut u8],
+    ) -> Result<*mut u8, *mut u8> {
+        Ok(HNumber::binop(gc, NumberOp::Add, args[0], args[1]))
+    }
+
+    /// Stands in for compiled code: adds the argument to the captured slot,
+    /// throws the receiver if the argument is nil.
+    unsafe extern "C" fn compiled_add(
+        gc: *mut CopyGC,
+        context: *mut u8,
+        receiver: *mut u8,
+        _args: *const *mut u8,
+        _argc: u32,
+        exception: *mut *mut u8,
+    ) -> *mut u8 {
+        let ctx = &*(context as *mut HContext);
+        let argument = ctx.lookup(0, 0);
+        if argument == NIL {
+            *exception = receiver;
+            return HOLE;
+        }
+        HNumber::binop(&mut *gc, NumberOp::Add, argument, ctx.lookup(1, 0))
+    }
+
+    /// Stands in for compiled code that allocates: collects, then returns
+    /// its first argument.
+    unsafe extern "C" fn compiled_collect(
+        gc: *mut CopyGC,
+        _context: *mut u8,
+        _receiver: *mut u8,
+        args: *const *mut u8,
+        _argc: u32,
+        _exception: *mut *mut u8,
+    ) -> *mut u8 {
+        (*gc).collect_garbage();
+        *args
+    }
+
+    #[test]
+    fn test_function_call() {
+        let mut gc = CopyGC::new();
+        let add = HFunction::new_native(&mut gc, native_add, 2);
+        assert_eq!(
+            HFunction::call(&mut gc, add, NIL, &[int(2), int(3)]),
+            Ok(int(5))
+        );
+        let error = HFunction::call(&mut gc, add, NIL, &[int(2)]).unwrap_err();
+        assert_eq!(string(error), "function expects 2 arguments, got 1");
+
+        let mut captured = HContext::new(&mut gc, NIL, 1);
+        unsafe { (*(captured as *mut HContext)).set_slot(0, int(40)) };
+        gc.add_root(&mut captured, RefType::Persistent);
+        let mut closure = HFunction::new(&mut gc, compiled_add, captured, NIL, 1);
+        let mut bound = HFunction::new(
+            &mut gc,
+            compiled_add,
+            BINDING_CONTEXT_TAG as *mut u8,
+            captured,
+            1,
+        );
+        gc.add_root(&mut closure, RefType::Persistent);
+        gc.add_root(&mut bound, RefType::Persistent);
+        gc.collect_garbage();
+
+        assert_eq!(
+            HFunction::call(&mut gc, closure, NIL, &[int(2)]),
+            Ok(int(42))
+        );
+        assert_eq!(HFunction::call(&mut gc, bound, NIL, &[int(1)]), Ok(int(41)));
+        let thrown = HString::from_str(&mut gc, "thrown");
+        assert_eq!(
+            HFunction::call(&mut gc, closure, thrown, &[NIL]),
+            Err(thrown)
+        );
+        assert!(HFunction::call(&mut gc, captured, NIL, &[]).is_err());
+
+        // the arguments stay rooted while the code runs
+        let collect = HFunction::new(&mut gc, compiled_collect, NIL, NIL, 1);
+        let argument = HString::from_str(&mut gc, "argument");
+        let result = HFunction::call(&mut gc, collect, NIL, &[argument]).unwrap();
+        assert_ne!(result, argument);
+        assert_eq!(string(result), "argument");
+    }
+
+    #[test]
+    fn test_map_operations() {
+        let mut gc = CopyGC::new();
+        let mut map = HMap::new(&mut gc, 0);
+        for i in 0..100 {
+            let key = HNumber::tag(i) as *mut u8;
+            map = HMap::set(&mut gc, map, key, HNumber::tag(i * 2) as *mut u8);
+        }
+        let table = unsafe { &*(map as *mut HMap) };
+        assert_eq!(table.count(), 100);
+        assert!(table.size() >= 128);
+        assert_eq!(
+            table.get(HNumber::tag(42) as *mut u8),
+            Some(HNumber::tag(84) as *mut u8)
+        );
+
+        for i in 0..50 {
+            assert!(table.delete(HNumber::tag(i) as *mut u8));
+        }
+        assert!(!table.delete(HNumber::tag(0) as *mut u8));
+        assert_eq!(table.count(), 50);
+        assert_eq!(table.deleted(), 50);
+        assert_eq!(table.get(HNumber::tag(10) as *mut u8), None);
+        assert_eq!(
+            table.get(HNumber::tag(60) as *mut u8),
+            Some(HNumber::tag(120) as *mut u8)
+        );
+
+        let mut keys: Vec<i64> = table.iter().map(|(key, _)| HNumber::untag(key)).collect();
+        keys.sort();
+        assert_eq!(keys, (50..100).collect::<Vec<i64>>());
+    }
+
+    #[test]
+    fn test_map_string_keys() {
+        let mut gc = CopyGC::new();
+        let mut map = HMap::new(&mut gc, 0);
+        let key = HString::from_str(&mut gc, "length");
+        map = HMap::set(&mut gc, map, key, HNumber::tag(1) as *mut u8);
+
+        let lookup = HString::from_str(&mut gc, "length");
+        let table = unsafe { &*(map as *mut HMap) };
+        assert_eq!(table.get(lookup), Some(HNumber::tag(1) as *mut u8));
+        let interned = gc.intern_str("length");
+        assert_eq!(table.get(interned), Some(HNumber::tag(1) as *mut u8));
+        assert_eq!(table.iter().next().unwrap().0, interned);
+    }
+
+    #[test]
+    fn test_map_identity_keys_survive_gc() {
+        let mut gc = CopyGC::new();
+        let mut map = HMap::new(&mut gc, 0);
+        let mut objects = Vec::new();
+        for i in 0..20 {
+            let obj = gc
+                .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+                .to_mut_ptr::<u8>();
+            HObject::set_map_s(obj, HeapTag::Nil as u8 as *mut u8);
+            HObject::set_proto_s(obj, HeapTag::Nil as u8 as *mut u8);
+            map = HMap::set(&mut gc, map, obj, HNumber::tag(i) as *mut u8);
+            objects.push(obj);
+        }
+        assert!(HMap::has_identity_keys(map));
+
+        gc.add_root(&mut map, RefType::Persistent);
+        for obj in objects.iter_mut() {
+            gc.add_root(obj, RefType::Persistent);
+        }
+        gc.collect_garbage();
+
+        let table = unsafe { &*(map as *mut HMap) };
+        for (i, &obj) in objects.iter().enumerate() {
+            assert_eq!(table.get(obj), Some(HNumber::tag(i as i64) as *mut u8));
+        }
+    }
+
+    #[test]
+    fn test_object_properties() {
+        let mut gc = CopyGC::new();
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        let one = HNumber::tag(1) as *mut u8;
+        let two = HNumber::tag(2) as *mut u8;
+        let mut proto = HObject::new(&mut gc, nil);
+        let mut obj = HObject::new(&mut gc, proto);
+        let key = gc.intern_str("x");
+
+        proto = HObject::set_property(&mut gc, proto, key, one).unwrap();
+        assert_eq!(HObject::get_property(obj, key), one);
+        assert!(HObject::has_property(obj, key));
+        assert!(!HObject::has_own_property(obj, key));
+
+        // stores shadow the prototype, deletes uncover it again
+        obj = HObject::set_property(&mut gc, obj, key, two).unwrap();
+        assert_eq!(HObject::get_property(obj, key), two);
+        assert_eq!(HObject::get_property(proto, key), one);
+        let (deleted, moved) = HObject::delete_property(&mut gc, obj, key).unwrap();
+        obj = moved;
+        assert!(deleted);
+        assert!(!HObject::delete_property(&mut gc, obj, key).unwrap().0);
+        assert_eq!(HObject::get_property(obj, key), one);
+
+        let missing = gc.intern_str("y");
+        assert_eq!(HObject::get_property(obj, missing), nil);
+        assert!(!HObject::has_property(obj, missing));
+
+        // prototype chains never become cycles
+        assert!(!HObject::set_proto_s(proto, obj));
+        assert!(!HObject::set_proto_s(obj, obj));
+        assert_eq!(HObject::proto_s(proto), nil);
+        assert_eq!(HObject::proto_s(obj), proto);
+
+        gc.add_root(&mut obj, RefType::Persistent);
+        gc.collect_garbage();
+        assert_eq!(HObject::get_property(obj, gc.intern_str("x")), one);
+    }
+
+    #[test]
+    fn test_shapes_are_shared() {
+        let mut gc = CopyGC::new();
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        let (x, y) = (gc.intern_str("x"), gc.intern_str("y"));
+        let mut a = HObject::new(&mut gc, nil);
+        let mut b = HObject::new(&mut gc, nil);
+        assert_eq!(HObject::map_s(a), HObject::map_s(b));
+
+        a = HObject::set_property(&mut gc, a, x, HNumber::tag(1) as *mut u8).unwrap();
+        a = HObject::set_property(&mut gc, a, y, HNumber::tag(2) as *mut u8).unwrap();
+        b = HObject::set_property(&mut gc, b, x, HNumber::tag(3) as *mut u8).unwrap();
+        let lookup = HString::from_str(&mut gc, "y");
+        b = HObject::set_property(&mut gc, b, lookup, HNumber::tag(4) as *mut u8).unwrap();
+
+        assert!(!HObject::is_dictionary(a));
+        assert_eq!(HObject::map_s(a), HObject::map_s(b));
+        let shape = unsafe { &*HObject::shape_s(a).unwrap() };
+        assert_eq!(shape.count(), 2);
+        assert_eq!(shape.lookup(y), Some(1));
+        assert_eq!(HObject::slot_s(b, 1), HNumber::tag(4) as *mut u8);
+
+        gc.add_root(&mut a, RefType::Persistent);
+        gc.add_root(&mut b, RefType::Persistent);
+        gc.collect_garbage();
+        assert_eq!(HObject::map_s(a), HObject::map_s(b));
+        let y = gc.intern_str("y");
+        assert_eq!(HObject::get_property(a, y), HNumber::tag(2) as *mut u8);
+        assert_eq!(HObject::get_property(b, y), HNumber::tag(4) as *mut u8);
+    }
+
+    #[test]
+    fn test_dictionary_fallback() {
+        let mut gc = CopyGC::new();
+        let nil = HeapTag::Nil as u8 as *mut u8;
+        let mut obj = HObject::new_with_slots(&mut gc, nil, 2);
+        for i in 0..3 {
+            let key = HNumber::tag(i) as *mut u8;
+            obj =
+                HObject::set_property(&mut gc, obj, key, HNumber::tag(i * 10) as *mut u8).unwrap();
+            assert_eq!(HObject::is_dictionary(obj), i == 2);
+        }
+        for i in 0..3 {
+            assert_eq!(
+                HObject::get_property(obj, HNumber::tag(i) as *mut u8),
+                HNumber::tag(i * 10) as *mut u8
+            );
+        }
+
+        let mut fast = HObject::new(&mut gc, nil);
+        let key = gc.intern_str("x");
+        fast = HObject::set_property(&mut gc, fast, key, HNumber::tag(1) as *mut u8).unwrap();
+        let (deleted, fast) = HObject::delete_property(&mut gc, fast, key).unwrap();
+        assert!(deleted);
+        assert!(HObject::is_dictionary(fast));
+        assert!(!HObject::has_property(fast, key));
+    }
+
+    #[test]
+    fn test_object_flags() {
+        let mut gc = CopyGC::new();
+        let mut obj = HObject::new(&mut gc, NIL);
+        gc.add_root(&mut obj, RefType::Persistent);
+        let (x, y) = (gc.intern_str("x"), gc.intern_str("y"));
+        obj = HObject::set_property(&mut gc, obj, x, int(1)).unwrap();
+
+        HObject::prevent_extensions(obj);
+        assert!(!HObject::is_extensible(obj) && !HObject::is_sealed(obj));
+        obj = HObject::set_property(&mut gc, obj, x, int(2)).unwrap();
+        let error = HObject::set_property(&mut gc, obj, y, int(1)).unwrap_err();
+        assert_eq!(
+            string(error),
+            "cannot add a property to a non-extensible object"
+        );
+
+        HObject::seal(obj);
+        let error = HObject::delete_property(&mut gc, obj, x).unwrap_err();
+        assert_eq!(string(error), "cannot delete a property of a sealed object");
+        let (deleted, _) = HObject::delete_property(&mut gc, obj, y).unwrap();
+        assert!(!deleted);
+        obj = HObject::set_property(&mut gc, obj, x, int(3)).unwrap();
+
+        HObject::freeze(obj);
+        let error = HObject::set_property(&mut gc, obj, x, int(4)).unwrap_err();
+        assert_eq!(string(error), "cannot change a property of a frozen object");
+        assert_eq!(HObject::get_property(obj, x), int(3));
+        assert!(HObject::is_extensible(HObject::copy(&mut gc, obj)));
+
+        let mut array = HArray::new(&mut gc, 0);
+        gc.add_root(&mut array, RefType::Persistent);
+        array = HArray::push(&mut gc, array, int(1)).unwrap();
+        array = HArray::set(&mut gc, array, 2, int(3)).unwrap();
+        HObject::prevent_extensions(array);
+        let error = HArray::push(&mut gc, array, int(4)).unwrap_err();
+        assert_eq!(
+            string(error),
+            "cannot add an element to a non-extensible array"
+        );
+        assert!(HArray::set(&mut gc, array, 1, int(2)).is_err());
+        array = HArray::set(&mut gc, array, 2, int(5)).unwrap();
+
+        HObject::seal(array);
+        assert!(HArray::pop(&mut gc, array).is_err());
+        assert!(HArray::splice(&mut gc, array, 0, 1, &[]).is_err());
+        let (spliced, _) = HArray::splice(&mut gc, array, 0, 1, &[int(6)]).unwrap();
+        array = spliced;
+        assert_eq!(HArray::get(array, 0), int(6));
+        let error = HArray::set_length(&mut gc, array, 1).unwrap_err();
+        assert_eq!(string(error), "cannot remove an element of a sealed array");
+        HArray::set_length(&mut gc, array, 4).unwrap();
+        HArray::set_length(&mut gc, array, 3).unwrap();
+
+        HObject::freeze(array);
+        let error = HArray::set(&mut gc, array, 0, int(7)).unwrap_err();
+        assert_eq!(string(error), "cannot change an element of a frozen array");
+        assert!(HArray::splice(&mut gc, array, 0, 1, &[int(7)]).is_err());
+        let error = HArray::set_length(&mut gc, array, 4).unwrap_err();
+        assert_eq!(string(error), "cannot change the length of a frozen array");
+        assert_eq!(HArray::length(array, false), 3);
+    }
+
+    #[test]
+    fn test_dense_array() {
+        let mut gc = CopyGC::new();
+        let mut array = HArray::new(&mut gc, 0);
+        for i in 0..100 {
+            array = HArray::push(&mut gc, array, int(i)).unwrap();
+        }
+        assert!(!HArray::is_sparse(array));
+        assert_eq!(HArray::length(array, false), 100);
+        assert_eq!(HArray::get(array, 42), int(42));
+        assert_eq!(HArray::get(array, 100), UNDEFINED);
+        assert!(!HArray::has(array, 100));
+
+        assert_eq!(HArray::pop(&mut gc, array).unwrap(), int(99));
+        assert_eq!(HArray::length(array, false), 99);
+
+        array = HArray::set(&mut gc, array, 120, int(120)).unwrap();
+        assert!(!HArray::is_sparse(array));
+        assert_eq!(HArray::length(array, false), 121);
+        HArray::set_length(&mut gc, array, 125).unwrap();
+        assert_eq!(HArray::length(array, true), 121);
+
+        gc.add_root(&mut array, RefType::Persistent);
+        gc.collect_garbage();
+        assert_eq!(HArray::get(array, 98), int(98));
+        assert_eq!(HArray::get(array, 99), UNDEFINED);
+    }
+
+    #[test]
+    fn test_sparse_array() {
+        let mut gc = CopyGC::new();
+        let mut array = HArray::new(&mut gc, 4);
+        array = HArray::set(&mut gc, array, 1, int(1)).unwrap();
+        array = HArray::set(&mut gc, array, 1_000_000, int(2)).unwrap();
+        assert!(HArray::is_sparse(array));
+        assert_eq!(HArray::length(array, false), 1_000_001);
+        assert_eq!(HArray::get(array, 1), int(1));
+        assert_eq!(HArray::get(array, 1_000_000), int(2));
+        assert_eq!(HArray::get(array, 500), UNDEFINED);
+        assert!(!HArray::has(array, 500));
+        assert!(HArray::has(array, 1));
+
+        HArray::set_length(&mut gc, array, 10).unwrap();
+        assert_eq!(HArray::get(array, 1_000_000), UNDEFINED);
+        assert_eq!(HArray::pop(&mut gc, array).unwrap(), UNDEFINED);
+        assert_eq!(HArray::length(array, true), 2);
+    }
+
+    #[test]
+    fn test_array_index_range() {
+        let mut gc = CopyGC::new();
+        let mut array = HArray::new(&mut gc, 0);
+        array = HArray::set(&mut gc, array, HArray::MAX_INDEX, int(1)).unwrap();
+        assert!(HArray::is_sparse(array));
+        assert_eq!(HArray::get(array, HArray::MAX_INDEX), int(1));
+        assert_eq!(HArray::indices(array), vec![HArray::MAX_INDEX]);
+        assert_eq!(HArray::length(array, false), HArray::MAX_INDEX as usize + 1);
+
+        let error = HArray::push(&mut gc, array, int(2)).unwrap_err();
+        assert_eq!(string(error), "array index out of range");
+        assert!(HArray::splice(&mut gc, array, 0, 0, &[int(0)]).is_err());
+        let length = HArray::MAX_INDEX as usize + 2;
+        assert!(HArray::set_length(&mut gc, array, length).is_err());
+    }
+
+    #[test]
+    fn test_array_splice() {
+        let mut gc = CopyGC::new();
+        let mut array = HArray::new(&mut gc, 0);
+        for i in 0..6 {
+            array = HArray::push(&mut gc, array, int(i)).unwrap();
+        }
+        let (array, removed) =
+            HArray::splice(&mut gc, array, 1, 2, &[int(10), int(11), int(12)]).unwrap();
+        let values: Vec<*mut u8> = (0..HArray::length(array, false) as u32)
+            .map(|i| HArray::get(array, i))
+            .collect();
+        assert_eq!(
+            values,
+            vec![int(0), int(10), int(11), int(12), int(3), int(4), int(5)]
+        );
+        assert_eq!(HArray::length(removed, false), 2);
+        assert_eq!(HArray::get(removed, 0), int(1));
+        assert_eq!(HArray::get(removed, 1), int(2));
     }
 }
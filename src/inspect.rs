@@ -0,0 +1,250 @@
+//! Readable rendering of values and the object graphs behind them, for
+//! debugging. Rendering only reads the heap and never allocates, so it works
+//! wherever the value itself is valid:
+//!
+//! ```ignore
+//! println!("{}", inspect(value));
+//! println!("{}", inspect(value).depth(1));
+//! ```
+//!
+//! Objects, arrays, maps and contexts are printed one entry per line.
+//! Containers nested deeper than the depth limit are shown by their type,
+//! containers that contain themselves as `<cycle>`.
+
+use crate::heap::*;
+use crate::runtime::type_name;
+use std::fmt;
+
+/// Renders a value as an indented tree when formatted, see `inspect`.
+#[derive(Copy, Clone)]
+pub struct Inspect {
+    value: *mut u8,
+    depth: usize,
+}
+
+pub fn inspect(value: *mut u8) -> Inspect {
+    Inspect {
+        value,
+        depth: Inspect::DEFAULT_DEPTH,
+    }
+}
+
+impl Inspect {
+    pub const DEFAULT_DEPTH: usize = 4;
+
+    /// Shows containers nested deeper than `depth` by their type only.
+    pub fn depth(self, depth: usize) -> Inspect {
+        Inspect { depth, ..self }
+    }
+}
+
+impl fmt::Display for Inspect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut printer = Printer {
+            out: f,
+            depth: self.depth,
+            path: Vec::new(),
+        };
+        printer.value(self.value, 0)
+    }
+}
+
+impl fmt::Debug for Inspect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// One line of a container.
+enum Entry {
+    Element(*mut u8),
+    Holes(usize),
+    Property(*mut u8, *mut u8),
+    Map(*mut u8, *mut u8),
+}
+
+struct Printer<'a, 'b> {
+    out: &'a mut fmt::Formatter<'b>,
+    depth: usize,
+    /// Containers enclosing the one being printed.
+    path: Vec<*mut u8>,
+}
+
+impl<'a, 'b> Printer<'a, 'b> {
+    fn value(&mut self, value: *mut u8, level: usize) -> fmt::Result {
+        match HValue::get_tag(value) {
+            HeapTag::Nil | HeapTag::Undefined => write!(self.out, "{}", type_name(value)),
+            HeapTag::Boolean => write!(self.out, "{}", HBoolean::value(value)),
+            HeapTag::Number => write!(self.out, "{}", HNumber::to_string_radix(value, 10)),
+            HeapTag::BigInt => write!(self.out, "{}n", HBigInt::to_string_radix(value, 10)),
+            HeapTag::String => {
+                let string = unsafe { (*(value as *mut HString)).to_rust_string() };
+                write!(self.out, "{:?}", string)
+            }
+            HeapTag::Function => {
+                let fun: &HFunction = unsafe { &*(value as *mut HFunction) };
+                let kind = if fun.is_native() { "native " } else { "" };
+                write!(self.out, "<{}function argc={}>", kind, fun.argc())
+            }
+            HeapTag::ExternData => write!(self.out, "<cdata>"),
+            HeapTag::Shape => {
+                let count = unsafe { (*(value as *mut HShape)).count() };
+                write!(self.out, "<shape count={}>", count)
+            }
+            HeapTag::Elements => {
+                let capacity = unsafe { (*(value as *mut HElements)).capacity() };
+                write!(self.out, "<elements capacity={}>", capacity)
+            }
+            HeapTag::Object | HeapTag::Array | HeapTag::Map | HeapTag::Context => {
+                self.container(value, level)
+            }
+        }
+    }
+
+    fn container(&mut self, value: *mut u8, level: usize) -> fmt::Result {
+        if self.path.contains(&value) {
+            return write!(self.out, "<cycle>");
+        }
+        if level >= self.depth {
+            return write!(self.out, "[{}]", type_name(value));
+        }
+        let (name, open, close) = match HValue::get_tag(value) {
+            HeapTag::Array => ("", "[", "]"),
+            HeapTag::Map => ("Map ", "{", "}"),
+            HeapTag::Context => ("Context ", "[", "]"),
+            _ => ("", "{", "}"),
+        };
+        let entries = entries(value);
+        if entries.is_empty() {
+            return write!(self.out, "{}{}{}", name, open, close);
+        }
+
+        self.path.push(value);
+        writeln!(self.out, "{}{}", name, open)?;
+        for entry in entries {
+            write!(self.out, "{:1$}", "", (level + 1) * 2)?;
+            match entry {
+                Entry::Element(item) => self.value(item, level + 1)?,
+                Entry::Holes(1) => write!(self.out, "<hole>")?,
+                Entry::Holes(count) => write!(self.out, "<{} holes>", count)?,
+                Entry::Property(key, item) => {
+                    if HValue::get_tag(key) == HeapTag::String {
+                        let key = unsafe { (*(key as *mut HString)).to_rust_string() };
+                        write!(self.out, "{}", key)?;
+                    } else {
+                        self.value(key, level + 1)?;
+                    }
+                    write!(self.out, ": ")?;
+                    self.value(item, level + 1)?;
+                }
+                Entry::Map(key, item) => {
+                    self.value(key, level + 1)?;
+                    write!(self.out, " => ")?;
+                    self.value(item, level + 1)?;
+                }
+            }
+            writeln!(self.out, ",")?;
+        }
+        self.path.pop();
+        write!(self.out, "{:1$}{2}", "", level * 2, close)
+    }
+}
+
+/// Entries of a container in print order. Runs of missing array elements
+/// are collapsed, own properties of arrays follow the elements.
+fn entries(value: *mut u8) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    match HValue::get_tag(value) {
+        HeapTag::Array => {
+            let mut next = 0;
+            for index in HArray::indices(value) {
+                let index = index as usize;
+                push_holes(&mut entries, index - next);
+                entries.push(Entry::Element(HArray::get(value, index as u32)));
+                next = index + 1;
+            }
+            push_holes(&mut entries, HArray::length(value, false) - next);
+        }
+        HeapTag::Map => {
+            let map: &HMap = unsafe { &*(value as *mut HMap) };
+            entries.extend(map.iter().map(|(key, item)| Entry::Map(key, item)));
+        }
+        HeapTag::Context => {
+            let ctx: &HContext = unsafe { &*(value as *mut HContext) };
+            entries.extend((0..ctx.slots()).map(|i| Entry::Element(ctx.get_slot(i) as *mut u8)));
+        }
+        _ => (),
+    }
+    if matches!(HValue::get_tag(value), HeapTag::Object | HeapTag::Array) {
+        for key in HObject::own_keys(value) {
+            let item = HObject::get_own_property(value, key).unwrap_or(NIL);
+            entries.push(Entry::Property(key, item));
+        }
+    }
+    entries
+}
+
+fn push_holes(entries: &mut Vec<Entry>, count: usize) {
+    if count > 0 {
+        entries.push(Entry::Holes(count));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc::copying::CopyGC;
+    use crate::testing::*;
+
+    #[test]
+    fn test_inspect_values() {
+        let mut gc = CopyGC::new();
+        assert_eq!(inspect(NIL).to_string(), "nil");
+        let half = HNumber::new(&mut gc, -0.5);
+        assert_eq!(inspect(half).to_string(), "-0.5");
+        let string = HString::from_str(&mut gc, "a \"b\"");
+        assert_eq!(inspect(string).to_string(), r#""a \"b\"""#);
+        let function = HFunction::new_native(&mut gc, |_, _, _| Ok(NIL), 2);
+        assert_eq!(inspect(function).to_string(), "<native function argc=2>");
+
+        let mut array = HArray::new(&mut gc, 0);
+        gc.add_root(&mut array, RefType::Persistent);
+        array = HArray::set(&mut gc, array, 1, TRUE).unwrap();
+        array = HArray::set(&mut gc, array, 5, array).unwrap();
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, int(1), array);
+        let mut ctx = HContext::new(&mut gc, NIL, 2);
+        unsafe { (*(ctx as *mut HContext)).set_slot(0, map) };
+        gc.add_root(&mut ctx, RefType::Persistent);
+        let name = HString::from_str(&mut gc, "name");
+        let name = gc.intern(name);
+        let mut obj = HObject::new(&mut gc, NIL);
+        obj = HObject::set_property(&mut gc, obj, name, ctx).unwrap();
+        let key = HString::from_str(&mut gc, "self");
+        let key = gc.intern(key);
+        obj = HObject::set_property(&mut gc, obj, key, obj).unwrap();
+
+        let expected = "{
+  name: Context [
+    Map {
+      1 => [
+        <hole>,
+        true,
+        <3 holes>,
+        <cycle>,
+      ],
+    },
+    nil,
+  ],
+  self: <cycle>,
+}";
+        assert_eq!(inspect(obj).to_string(), expected);
+        assert_eq!(
+            inspect(obj).depth(2).to_string(),
+            "{\n  name: Context [\n    [map],\n    nil,\n  ],\n  self: <cycle>,\n}"
+        );
+        assert_eq!(inspect(obj).depth(0).to_string(), "[object]");
+        let empty = HObject::new(&mut gc, NIL);
+        assert_eq!(format!("{:?}", inspect(empty)), "{}");
+    }
+}
@@ -1,9 +1,15 @@
 #![feature(const_fn)]
 #![feature(const_raw_ptr_to_usize_cast)]
+pub mod bignum;
 pub mod gc;
 pub mod heap;
+pub mod inspect;
 pub mod lir_ins;
 pub mod mem;
 pub mod os;
+pub mod runtime;
+#[cfg(test)]
+mod testing;
 pub mod utils;
+pub mod value;
 pub mod zalloc;
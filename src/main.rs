@@ -3,14 +3,84 @@ extern crate exvm;
 
 use exvm::gc::copying::{formatted_size, CopyGC};
 use exvm::heap::*;
+use exvm::inspect::inspect;
 use exvm::zalloc::*;
 
+/// Builds a small object graph rooted in a context:
+/// `ctx[0] -> object`, `object.proto -> proto`, `proto.proto -> nil`.
+fn build_demo_heap(gc: &mut CopyGC, root: &mut *mut u8) {
+    let ctx = gc
+        .alloc_tagged(HeapTag::Context, 3 * FIELD_SIZE)
+        .to_mut_ptr::<u8>();
+    let obj = gc
+        .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+        .to_mut_ptr::<u8>();
+    let proto = gc
+        .alloc_tagged(HeapTag::Object, 3 * FIELD_SIZE)
+        .to_mut_ptr::<u8>();
+
+    unsafe {
+        *(ctx.offset(HContext::SLOTS_OFFSET) as *mut u32) = 1;
+        (*(ctx as *mut HContext)).set_slot(0, obj);
+    }
+    HObject::set_map_s(obj, HeapTag::Nil as u8 as *mut u8);
+    HObject::set_map_s(proto, HeapTag::Nil as u8 as *mut u8);
+    HObject::set_proto_s(proto, NIL);
+    HObject::set_proto_s(obj, proto);
+
+    *root = ctx;
+    gc.add_root(root, RefType::Persistent);
+}
+
+/// `exvm --retainers [addr]` prints the retaining path of the object at
+/// `addr` (hex), or of every object in the heap snapshot.
+fn print_retainers(gc: &CopyGC, addr: Option<&String>) {
+    let snapshot = gc.heap_snapshot();
+    let mut targets: Vec<usize> = match addr {
+        Some(addr) => {
+            let addr = usize::from_str_radix(addr.trim_start_matches("0x"), 16)
+                .expect("--retainers expects a hex address");
+            vec![addr]
+        }
+        None => snapshot.nodes.keys().cloned().collect(),
+    };
+    targets.sort();
+
+    for target in targets {
+        match snapshot.retaining_path(target as *mut u8) {
+            Some(path) => println!("{}", path),
+            None => println!("0x{:x} is not reachable", target),
+        }
+    }
+}
+
+/// `exvm --inspect [depth]` prints the object graph of the demo heap.
+fn print_inspect(root: *mut u8, depth: Option<&String>) {
+    let mut root = inspect(root);
+    if let Some(depth) = depth {
+        root = root.depth(depth.parse().expect("--inspect expects a depth"));
+    }
+    println!("{}", root);
+}
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
     let mut gc = CopyGC::new();
     let mut mbs = 0;
     let my_number = gc.alloc_tagged(HeapTag::Number, 8);
+    let flag = args.get(1).map(String::as_str);
+    let mut root = std::ptr::null_mut();
+    if let Some("--retainers") | Some("--inspect") = flag {
+        build_demo_heap(&mut gc, &mut root);
+    }
     gc.collect_garbage();
 
+    match flag {
+        Some("--retainers") => print_retainers(&gc, args.get(2)),
+        Some("--inspect") => print_inspect(root, args.get(2)),
+        _ => (),
+    }
+
     unsafe {
         *my_number.to_mut_ptr::<i64>() = 42;
         println!("{}", *my_number.to_ptr::<i64>());
@@ -172,3 +172,38 @@ pub fn mprotect(ptr: *const u8, size: usize, prot: ProtType) {
         panic!("mprotect() failed");
     }
 }
+
+/// Reserves `size` bytes of address space without committing memory, pages
+/// have to be made accessible with `mprotect` before use.
+#[cfg(target_family = "unix")]
+pub fn reserve(size: usize) -> *const u8 {
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            size,
+            libc::PROT_NONE,
+            libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE,
+            -1,
+            0,
+        ) as *mut libc::c_void
+    };
+
+    if ptr == libc::MAP_FAILED {
+        panic!("mmap failed");
+    }
+
+    ptr as *const u8
+}
+
+/// Gives the memory of reserved pages back to the system, the address range
+/// stays reserved.
+#[cfg(target_family = "unix")]
+pub fn decommit(ptr: *const u8, size: usize) {
+    let res = unsafe { libc::madvise(ptr as *mut libc::c_void, size, libc::MADV_DONTNEED) };
+
+    if res != 0 {
+        panic!("madvise() failed");
+    }
+
+    mprotect(ptr, size, ProtType::None);
+}
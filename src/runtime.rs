@@ -0,0 +1,679 @@
+//! Runtime functions behind LIR instructions that are not inlined.
+
+use crate::gc::copying::CopyGC;
+use crate::gc::handles::{HandleScope, Local};
+use crate::heap::*;
+use std::collections::HashMap;
+
+/// Name `TypeOf` returns for `value`.
+pub fn type_name(value: *mut u8) -> &'static str {
+    match HValue::get_tag(value) {
+        HeapTag::Nil => "nil",
+        HeapTag::Undefined => "undefined",
+        HeapTag::Boolean => "boolean",
+        HeapTag::Number => "number",
+        HeapTag::BigInt => "bigint",
+        HeapTag::String => "string",
+        HeapTag::Object => "object",
+        HeapTag::Array => "array",
+        HeapTag::Function => "function",
+        HeapTag::Context => "context",
+        HeapTag::Map => "map",
+        HeapTag::ExternData => "cdata",
+        HeapTag::Shape => "shape",
+        HeapTag::Elements => "elements",
+    }
+}
+
+/// `TypeOf`: the type name of `value` as an interned string.
+pub fn type_of(gc: &mut CopyGC, value: *mut u8) -> *mut u8 {
+    gc.intern_str(type_name(value))
+}
+
+/// `SizeOf`: code points of a string, length of an array, number of own
+/// properties of an object and entries of a map. Everything else has size 0.
+pub fn size_of(value: *mut u8) -> *mut u8 {
+    let size = match HValue::get_tag(value) {
+        HeapTag::String => unsafe { (*(value as *mut HString)).char_length() as usize },
+        HeapTag::Array => HArray::length(value, false),
+        HeapTag::Object => HObject::own_keys(value).len(),
+        HeapTag::Map => unsafe { (*(value as *mut HMap)).count() as usize },
+        _ => 0,
+    };
+    HNumber::tag(size as i64) as *mut u8
+}
+
+/// `KeysOf`: a new array with the indices of the present elements of an
+/// array followed by its own property keys, the own property keys of an
+/// object or the keys of a map. Other values have no keys.
+pub fn keys_of(gc: &mut CopyGC, value: *mut u8) -> *mut u8 {
+    let mut keys: Vec<*mut u8> = match HValue::get_tag(value) {
+        HeapTag::Array => HArray::indices(value)
+            .into_iter()
+            .map(|index| HNumber::tag(index as i64) as *mut u8)
+            .chain(HObject::own_keys(value))
+            .collect(),
+        HeapTag::Object => HObject::own_keys(value),
+        HeapTag::Map => unsafe { (*(value as *mut HMap)).iter().map(|(key, _)| key).collect() },
+        _ => Vec::new(),
+    };
+
+    for key in keys.iter_mut() {
+        gc.add_root(key, RefType::Persistent);
+    }
+    let mut result = HArray::new(gc, keys.len() as u32);
+    for (i, key) in keys.iter().enumerate() {
+        result = HArray::set(gc, result, i as u32, *key).expect("new arrays are extensible");
+    }
+    for key in keys.iter_mut() {
+        gc.remove_root(key);
+    }
+    result
+}
+
+/// `ToString`: strings are returned as they are, other values are
+/// formatted into a new string. Objects with a `toString` method are
+/// formatted by calling it, which has to return a string. Arrays join their
+/// elements with commas, holes and arrays already being joined are empty.
+pub fn to_string(gc: &mut CopyGC, value: *mut u8) -> Result<*mut u8, *mut u8> {
+    if HValue::get_tag(value) == HeapTag::String {
+        return Ok(value);
+    }
+    let mut scope = HandleScope::new(gc);
+    let text = format_value(&mut scope, value, &mut Vec::new())?;
+    Ok(HString::from_str(&mut scope, &text))
+}
+
+fn format_value<'s>(
+    scope: &mut HandleScope<'s>,
+    value: *mut u8,
+    joining: &mut Vec<Local<'s, HValue>>,
+) -> Result<String, *mut u8> {
+    let text = match HValue::get_tag(value) {
+        HeapTag::Nil | HeapTag::Undefined => type_name(value).to_string(),
+        HeapTag::Boolean => HBoolean::value(value).to_string(),
+        HeapTag::Number | HeapTag::BigInt => HNumber::to_string_radix(value, 10),
+        HeapTag::String => unsafe { (*(value as *mut HString)).to_rust_string() },
+        HeapTag::Array => {
+            if joining.iter().any(|array| array.get(scope) == value) {
+                return Ok(String::new());
+            }
+            let array: Local<HValue> = scope.local(value);
+            joining.push(array);
+            let mut parts = Vec::new();
+            for i in 0..HArray::length(value, false) as u32 {
+                let (array, i) = (array.get(scope), i);
+                parts.push(if HArray::has(array, i) {
+                    format_value(scope, HArray::get(array, i), joining)?
+                } else {
+                    String::new()
+                });
+            }
+            joining.pop();
+            parts.join(",")
+        }
+        HeapTag::Object => {
+            let object: Local<HValue> = scope.local(value);
+            let name = scope.intern_str("toString");
+            let object = object.get(scope);
+            let method = HObject::get_property(object, name);
+            if !HValue::is_heap_pointer(method) || HValue::get_tag(method) != HeapTag::Function {
+                return Ok("[object]".to_string());
+            }
+            let result = HFunction::call(scope, method, object, &[])?;
+            if HValue::get_tag(result) != HeapTag::String {
+                return Err(HString::from_str(scope, "toString has to return a string"));
+            }
+            unsafe { (*(result as *mut HString)).to_rust_string() }
+        }
+        _ => format!("[{}]", type_name(value)),
+    };
+    Ok(text)
+}
+
+/// `ToNumber`: numbers are returned as they are, booleans are 0 or 1 and
+/// nil is 0. Strings are parsed with `HNumber::parse` after trimming
+/// whitespace, blank strings are 0. Everything else is NaN.
+pub fn to_number(gc: &mut CopyGC, value: *mut u8) -> *mut u8 {
+    match HValue::get_tag(value) {
+        HeapTag::Number | HeapTag::BigInt => value,
+        HeapTag::Nil => HNumber::tag(0) as *mut u8,
+        HeapTag::Boolean => HNumber::tag(HBoolean::value(value) as i64) as *mut u8,
+        HeapTag::String => {
+            let text = unsafe { (*(value as *mut HString)).to_rust_string() };
+            let text = text.trim();
+            if text.is_empty() {
+                return HNumber::tag(0) as *mut u8;
+            }
+            HNumber::parse(gc, text).unwrap_or_else(|| HNumber::new(gc, f64::NAN))
+        }
+        _ => HNumber::new(gc, f64::NAN),
+    }
+}
+
+/// Copies a function or `ExternData` for `clone_deep`, returns the copy or
+/// an exception.
+pub type CloneHook = fn(&mut CopyGC, *mut u8) -> Result<*mut u8, *mut u8>;
+
+/// `Clone`: copy of an object, array or map with its own property, element
+/// and entry storage, the values in it are shared with `value`. Strings and
+/// numbers are immutable and returned as they are, like all other values.
+pub fn clone_shallow(gc: &mut CopyGC, value: *mut u8) -> *mut u8 {
+    if !HValue::is_heap_pointer(value) {
+        return value;
+    }
+    match HValue::get_tag(value) {
+        HeapTag::Object | HeapTag::Array => HObject::copy(gc, value),
+        HeapTag::Map => HValue::shallow_copy(gc, &mut [value]),
+        _ => value,
+    }
+}
+
+/// Structured clone: copies objects, arrays and maps together with the
+/// objects, arrays and maps they reference. Values reachable more than once,
+/// cycles included, are copied once, so the clone shares exactly what the
+/// original shares. Prototypes are not copied. Functions and `ExternData`
+/// are copied by `hook`, without one cloning them throws.
+pub fn clone_deep(
+    gc: &mut CopyGC,
+    value: *mut u8,
+    hook: Option<CloneHook>,
+) -> Result<*mut u8, *mut u8> {
+    let mut scope = HandleScope::new(gc);
+    let mut cloner = Cloner {
+        hook,
+        originals: Vec::new(),
+        copies: Vec::new(),
+        pending: Vec::new(),
+        index: HashMap::new(),
+        collections: scope.collections(),
+    };
+    let result = cloner.clone_value(&mut scope, value)?;
+    let result: Local<HValue> = scope.local(result);
+    while let Some(copy) = cloner.pending.pop() {
+        if HValue::get_tag(copy.get(&scope)) == HeapTag::Map {
+            cloner.clone_entries(&mut scope, copy)?;
+        } else {
+            cloner.clone_fields(&mut scope, copy)?;
+        }
+    }
+    Ok(result.get(&scope))
+}
+
+/// Visited table of `clone_deep`.
+struct Cloner<'s> {
+    hook: Option<CloneHook>,
+    originals: Vec<Local<'s, HValue>>,
+    copies: Vec<Local<'s, HValue>>,
+    /// Copies whose fields still refer to the originals.
+    pending: Vec<Local<'s, HValue>>,
+    /// Position in `originals` by address, stale once objects moved.
+    index: HashMap<usize, usize>,
+    collections: usize,
+}
+
+impl<'s> Cloner<'s> {
+    fn sync_index(&mut self, scope: &HandleScope<'s>) {
+        if scope.collections() == self.collections {
+            return;
+        }
+        self.collections = scope.collections();
+        self.index = self
+            .originals
+            .iter()
+            .enumerate()
+            .map(|(i, original)| (original.get(scope) as usize, i))
+            .collect();
+    }
+
+    fn record(
+        &mut self,
+        scope: &HandleScope<'s>,
+        original: Local<'s, HValue>,
+        copy: Local<'s, HValue>,
+    ) {
+        self.originals.push(original);
+        self.copies.push(copy);
+        self.sync_index(scope);
+        self.index
+            .insert(original.get(scope) as usize, self.originals.len() - 1);
+    }
+
+    fn clone_value(
+        &mut self,
+        scope: &mut HandleScope<'s>,
+        value: *mut u8,
+    ) -> Result<*mut u8, *mut u8> {
+        if !HValue::is_heap_pointer(value) {
+            return Ok(value);
+        }
+        let tag = HValue::get_tag(value);
+        if !matches!(
+            tag,
+            HeapTag::Object
+                | HeapTag::Array
+                | HeapTag::Map
+                | HeapTag::Function
+                | HeapTag::ExternData
+        ) {
+            return Ok(value);
+        }
+        self.sync_index(scope);
+        if let Some(&i) = self.index.get(&(value as usize)) {
+            return Ok(self.copies[i].get(scope));
+        }
+
+        let original: Local<HValue> = scope.local(value);
+        let (copy, pending) = match (tag, self.hook) {
+            (HeapTag::Function, Some(hook)) | (HeapTag::ExternData, Some(hook)) => {
+                (hook(scope, value)?, false)
+            }
+            (HeapTag::Function, None) | (HeapTag::ExternData, None) => {
+                let message = format!("cannot clone {}", type_name(value));
+                return Err(HString::from_str(scope, &message));
+            }
+            _ => (clone_shallow(scope, value), true),
+        };
+        let copy: Local<HValue> = scope.local(copy);
+        if pending {
+            self.pending.push(copy);
+        }
+        self.record(scope, original, copy);
+        Ok(copy.get(scope))
+    }
+
+    /// Replaces the values in the fields of `copy` by their clones.
+    fn clone_fields(
+        &mut self,
+        scope: &mut HandleScope<'s>,
+        copy: Local<'s, HValue>,
+    ) -> Result<(), *mut u8> {
+        let mut collections = scope.collections();
+        let mut slots = value_slots(copy.get(scope));
+        for i in 0..slots.len() {
+            let value = unsafe { load_field(slots[i]) };
+            let cloned = self.clone_value(scope, value)?;
+            if scope.collections() != collections {
+                collections = scope.collections();
+                slots = value_slots(copy.get(scope));
+            }
+            unsafe { store_field(slots[i], cloned) };
+        }
+        Ok(())
+    }
+
+    /// Refills the map `copy` with clones of its entries. Collections
+    /// rehash maps keyed by objects, so the entries can't be replaced slot
+    /// by slot like fields.
+    fn clone_entries(
+        &mut self,
+        scope: &mut HandleScope<'s>,
+        copy: Local<'s, HValue>,
+    ) -> Result<(), *mut u8> {
+        let entries: Vec<(*mut u8, *mut u8)> =
+            unsafe { (*(copy.get(scope) as *mut HMap)).iter().collect() };
+        let mut cloned: Vec<(Local<'s, HValue>, Local<'s, HValue>)> = entries
+            .into_iter()
+            .map(|(key, value)| (scope.local(key), scope.local(value)))
+            .collect();
+        for (key, value) in cloned.iter_mut() {
+            let clone = self.clone_value(scope, key.get(scope))?;
+            *key = scope.local(clone);
+            let clone = self.clone_value(scope, value.get(scope))?;
+            *value = scope.local(clone);
+        }
+
+        // the same number of entries fits without growing
+        unsafe { (*(copy.get(scope) as *mut HMap)).clear() };
+        for (key, value) in cloned {
+            let (map, key, value) = (copy.get(scope), key.get(scope), value.get(scope));
+            let map = HMap::set(scope, map, key, value);
+            debug_assert!(map == copy.get(scope), "clone_entries grew the map");
+        }
+        Ok(())
+    }
+}
+
+/// Fields of an object or array that hold its values: in-object properties,
+/// values in its dictionary and elements. Property keys and array indices
+/// are not values.
+fn value_slots(value: *mut u8) -> Vec<*mut *mut u8> {
+    let mut slots = Vec::new();
+    let mut stores = vec![value];
+    let mut i = 0;
+    while i < stores.len() {
+        let store = stores[i];
+        let mut key = NIL;
+        unsafe {
+            (*HValue::cast(store)).visit_slots(|name, slot| match name {
+                SlotName::Slot(_) => slots.push(slot),
+                SlotName::Map | SlotName::Elements if HValue::is_heap_pointer(load_field(slot)) => {
+                    stores.push(load_field(slot))
+                }
+                SlotName::MapKey(_) => key = load_field(slot),
+                SlotName::MapValue(_) if key != NIL => slots.push(slot),
+                _ => (),
+            });
+        }
+        i += 1;
+    }
+    slots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn keys(gc: &mut CopyGC, value: *mut u8) -> Vec<*mut u8> {
+        let array = keys_of(gc, value);
+        (0..HArray::length(array, false) as u32)
+            .map(|i| HArray::get(array, i))
+            .collect()
+    }
+
+    #[test]
+    fn test_type_of_every_tag() {
+        let mut gc = CopyGC::new();
+        let values = [
+            (NIL, HeapTag::Nil, "nil"),
+            (UNDEFINED, HeapTag::Undefined, "undefined"),
+            (TRUE, HeapTag::Boolean, "boolean"),
+            (int(1), HeapTag::Number, "number"),
+            (HNumber::new(&mut gc, 0.5), HeapTag::Number, "number"),
+            (
+                HBigInt::parse(&mut gc, "0x1000000000000000000000000").unwrap(),
+                HeapTag::BigInt,
+                "bigint",
+            ),
+            (HString::from_str(&mut gc, "s"), HeapTag::String, "string"),
+            (HObject::new(&mut gc, NIL), HeapTag::Object, "object"),
+            (HArray::new(&mut gc, 0), HeapTag::Array, "array"),
+            (
+                HFunction::new_native(&mut gc, |_, _, _| Ok(NIL), 0),
+                HeapTag::Function,
+                "function",
+            ),
+            (HContext::new(&mut gc, NIL, 0), HeapTag::Context, "context"),
+            (HMap::new(&mut gc, 0), HeapTag::Map, "map"),
+            (
+                gc.alloc_tagged(HeapTag::ExternData, 8).to_mut_ptr::<u8>(),
+                HeapTag::ExternData,
+                "cdata",
+            ),
+            (gc.root_shape(), HeapTag::Shape, "shape"),
+            (HElements::new(&mut gc, 0), HeapTag::Elements, "elements"),
+        ];
+        for &(value, tag, name) in values.iter() {
+            assert_eq!(HValue::get_tag(value), tag);
+            let type_string = type_of(&mut gc, value);
+            assert_eq!(string(type_string), name);
+            assert_eq!(type_of(&mut gc, value), type_string);
+            if !matches!(
+                tag,
+                HeapTag::String | HeapTag::Object | HeapTag::Array | HeapTag::Map
+            ) {
+                assert_eq!(size_of(value), int(0));
+                assert!(keys(&mut gc, value).is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_of_and_keys_of() {
+        let mut gc = CopyGC::new();
+        assert_eq!(size_of(HString::from_str(&mut gc, "héllo")), int(5));
+
+        let mut obj = HObject::new(&mut gc, NIL);
+        gc.add_root(&mut obj, RefType::Persistent);
+        for name in &["a", "b", "c"] {
+            let key = HString::from_str(&mut gc, name);
+            obj = HObject::set_property(&mut gc, obj, key, int(1)).unwrap();
+        }
+        assert_eq!(size_of(obj), int(3));
+        let names: Vec<String> = keys(&mut gc, obj).into_iter().map(string).collect();
+        assert_eq!(names, ["a", "b", "c"]);
+
+        let mut array = HArray::new(&mut gc, 0);
+        array = HArray::set(&mut gc, array, 1, TRUE).unwrap();
+        array = HArray::set(&mut gc, array, 3, FALSE).unwrap();
+        assert_eq!(size_of(array), int(4));
+        assert_eq!(keys(&mut gc, array), [int(1), int(3)]);
+
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, int(7), NIL);
+        map = HMap::set(&mut gc, map, obj, NIL);
+        assert_eq!(size_of(map), int(2));
+        let mut map_keys = keys(&mut gc, map);
+        map_keys.sort();
+        let mut expected = vec![int(7), obj];
+        expected.sort();
+        assert_eq!(map_keys, expected);
+    }
+
+    #[test]
+    fn test_clone_shallow() {
+        let mut gc = CopyGC::new();
+        assert_eq!(clone_shallow(&mut gc, int(3)), int(3));
+        let string = HString::from_str(&mut gc, "s");
+        assert_eq!(clone_shallow(&mut gc, string), string);
+
+        let mut inner = HObject::new(&mut gc, NIL);
+        gc.add_root(&mut inner, RefType::Persistent);
+        let mut obj = HObject::new_with_slots(&mut gc, NIL, 1);
+        gc.add_root(&mut obj, RefType::Persistent);
+        for name in &["a", "b"] {
+            let name = key(&mut gc, name);
+            obj = HObject::set_property(&mut gc, obj, name, inner).unwrap();
+        }
+        assert!(HObject::is_dictionary(obj));
+        let copy = clone_shallow(&mut gc, obj);
+        let (a, b) = (key(&mut gc, "a"), key(&mut gc, "b"));
+        HObject::set_property(&mut gc, copy, a, int(1)).unwrap();
+        HObject::delete_property(&mut gc, copy, b).unwrap();
+        assert_eq!(HObject::get_property(copy, a), int(1));
+        assert_eq!(HObject::get_property(obj, a), inner);
+        assert!(HObject::has_own_property(obj, b));
+
+        let mut array = HArray::new(&mut gc, 0);
+        gc.add_root(&mut array, RefType::Persistent);
+        array = HArray::push(&mut gc, array, inner).unwrap();
+        let copy = clone_shallow(&mut gc, array);
+        assert_eq!(HArray::get(copy, 0), inner);
+        HArray::set(&mut gc, copy, 0, NIL).unwrap();
+        assert_eq!(HArray::get(array, 0), inner);
+
+        let mut map = HMap::new(&mut gc, 0);
+        map = HMap::set(&mut gc, map, inner, TRUE);
+        let copy = clone_shallow(&mut gc, map);
+        HMap::set(&mut gc, copy, int(1), FALSE);
+        unsafe {
+            assert_eq!((*(copy as *mut HMap)).get(inner), Some(TRUE));
+            assert_eq!((*(map as *mut HMap)).count(), 1);
+        }
+    }
+
+    #[test]
+    fn test_clone_deep() {
+        let mut gc = CopyGC::new();
+        let mut scope = HandleScope::new(&mut gc);
+        let shared = HObject::new(&mut scope, NIL);
+        let shared: Local<HObject> = scope.local(shared);
+        let root = HObject::new(&mut scope, NIL);
+        let root: Local<HObject> = scope.local(root);
+        let (name, this) = (key(&mut scope, "name"), key(&mut scope, "this"));
+        let (first, second) = (key(&mut scope, "first"), key(&mut scope, "second"));
+        let value = HString::from_str(&mut scope, "shared");
+        let target = shared.get(&scope);
+        let target = HObject::set_property(&mut scope, target, name, value).unwrap();
+        shared.set(&mut scope, target);
+
+        // a cycle, the same object twice and an array of objects with a
+        // function, whose clone hook moves everything
+        let target = root.get(&scope);
+        let target = HObject::set_property(&mut scope, target, this, target).unwrap();
+        let value = shared.get(&scope);
+        let target = HObject::set_property(&mut scope, target, first, value).unwrap();
+        let target = HObject::set_property(&mut scope, target, second, value).unwrap();
+        root.set(&mut scope, target);
+        let array = HArray::new(&mut scope, 0);
+        let array: Local<HArray> = scope.local(array);
+        for _ in 0..100 {
+            let element = HObject::new(&mut scope, NIL);
+            let target = array.get(&scope);
+            let target = HArray::push(&mut scope, target, element).unwrap();
+            array.set(&mut scope, target);
+        }
+        let function = HFunction::new_native(&mut scope, |_, _, _| Ok(NIL), 0);
+        let target = array.get(&scope);
+        let target = HArray::push(&mut scope, target, function).unwrap();
+        array.set(&mut scope, target);
+        let mut map = HMap::new(&mut scope, 0);
+        let (value, target) = (shared.get(&scope), array.get(&scope));
+        map = HMap::set(&mut scope, map, value, target);
+        let target = root.get(&scope);
+        let items = key(&mut scope, "items");
+        let target = HObject::set_property(&mut scope, target, items, map).unwrap();
+        root.set(&mut scope, target);
+
+        let collections = scope.collections();
+        let original = root.get(&scope);
+        let hook: CloneHook = |gc, mut function| {
+            gc.add_root(&mut function, RefType::Persistent);
+            gc.collect_garbage();
+            gc.remove_root(&mut function);
+            Ok(function)
+        };
+        let copy = clone_deep(&mut scope, original, Some(hook)).unwrap();
+        assert!(scope.collections() > collections);
+        let (name, this) = (key(&mut scope, "name"), key(&mut scope, "this"));
+        let (first, second) = (key(&mut scope, "first"), key(&mut scope, "second"));
+        let items = key(&mut scope, "items");
+        let original = root.get(&scope);
+        assert_ne!(copy, original);
+        assert_eq!(HObject::get_property(copy, this), copy);
+        let copied = HObject::get_property(copy, first);
+        assert_ne!(copied, shared.get(&scope));
+        assert_eq!(HObject::get_property(copy, second), copied);
+        assert_eq!(string(HObject::get_property(copied, name)), "shared");
+
+        let map = HObject::get_property(copy, items);
+        let elements = unsafe { (*(map as *mut HMap)).get(copied).unwrap() };
+        assert_ne!(elements, array.get(&scope));
+        assert_eq!(HArray::length(elements, false), 101);
+        let (element, original_element) = (
+            HArray::get(elements, 99),
+            HArray::get(array.get(&scope), 99),
+        );
+        assert_eq!(HValue::get_tag(element), HeapTag::Object);
+        assert_ne!(element, original_element);
+        assert_eq!(
+            HArray::get(elements, 100),
+            HArray::get(array.get(&scope), 100)
+        );
+    }
+
+    #[test]
+    fn test_clone_deep_functions() {
+        let mut gc = CopyGC::new();
+        let mut array = HArray::new(&mut gc, 0);
+        gc.add_root(&mut array, RefType::Persistent);
+        let function = HFunction::new_native(&mut gc, |_, _, _| Ok(NIL), 0);
+        array = HArray::push(&mut gc, array, function).unwrap();
+        let function = HArray::get(array, 0);
+        array = HArray::push(&mut gc, array, function).unwrap();
+
+        let error = clone_deep(&mut gc, array, None).unwrap_err();
+        assert_eq!(string(error), "cannot clone function");
+
+        let copy = clone_deep(&mut gc, array, Some(|_, _| Ok(TRUE))).unwrap();
+        assert_eq!(HArray::get(copy, 0), TRUE);
+        assert_eq!(HArray::get(copy, 1), TRUE);
+        let copy = clone_deep(&mut gc, array, Some(|_, function| Ok(function))).unwrap();
+        assert_eq!(HArray::get(copy, 0), HArray::get(array, 1));
+    }
+
+    #[test]
+    fn test_clone_deep_identity_keys() {
+        let mut gc = CopyGC::new();
+        let mut scope = HandleScope::new(&mut gc);
+        let map = HMap::new(&mut scope, 0);
+        let map: Local<HMap> = scope.local(map);
+        for _ in 0..40 {
+            let function = HFunction::new_native(&mut scope, |_, _, _| Ok(NIL), 0);
+            let function: Local<HFunction> = scope.local(function);
+            let value = HObject::new(&mut scope, NIL);
+            let (target, function) = (map.get(&scope), function.get(&scope));
+            let target = HMap::set(&mut scope, target, function, value);
+            map.set(&mut scope, target);
+        }
+
+        // every collection rehashes the copy while its values are cloned
+        let hook: CloneHook = |gc, mut function| {
+            gc.add_root(&mut function, RefType::Persistent);
+            gc.collect_garbage();
+            gc.remove_root(&mut function);
+            Ok(function)
+        };
+        let original = map.get(&scope);
+        let copy = clone_deep(&mut scope, original, Some(hook)).unwrap();
+        let (original, copy) = unsafe { (&*(map.get(&scope) as *mut HMap), &*(copy as *mut HMap)) };
+        assert_eq!(copy.count(), 40);
+        for (key, value) in original.iter() {
+            let cloned = copy.get(key).unwrap();
+            assert_eq!(HValue::get_tag(cloned), HeapTag::Object);
+            assert_ne!(cloned, value);
+        }
+    }
+
+    fn text(gc: &mut CopyGC, value: *mut u8) -> String {
+        string(to_string(gc, value).unwrap())
+    }
+
+    #[test]
+    fn test_to_string() {
+        let mut gc = CopyGC::new();
+        assert_eq!(text(&mut gc, NIL), "nil");
+        assert_eq!(text(&mut gc, FALSE), "false");
+        let half = HNumber::new(&mut gc, 1.5);
+        assert_eq!(text(&mut gc, half), "1.5");
+        let s = HString::from_str(&mut gc, "s");
+        assert_eq!(to_string(&mut gc, s), Ok(s));
+
+        let mut array = HArray::new(&mut gc, 0);
+        gc.add_root(&mut array, RefType::Persistent);
+        array = HArray::push(&mut gc, array, int(1)).unwrap();
+        array = HArray::set(&mut gc, array, 2, UNDEFINED).unwrap();
+        array = HArray::push(&mut gc, array, array).unwrap();
+        assert_eq!(text(&mut gc, array), "1,,undefined,");
+
+        let mut obj = HObject::new(&mut gc, NIL);
+        gc.add_root(&mut obj, RefType::Persistent);
+        assert_eq!(text(&mut gc, obj), "[object]");
+        let method =
+            HFunction::new_native(&mut gc, |gc, _, _| Ok(HString::from_str(gc, "custom")), 0);
+        let name = key(&mut gc, "toString");
+        obj = HObject::set_property(&mut gc, obj, name, method).unwrap();
+        assert_eq!(text(&mut gc, obj), "custom");
+        let method = HFunction::new_native(&mut gc, |_, _, _| Ok(NIL), 0);
+        let name = key(&mut gc, "toString");
+        obj = HObject::set_property(&mut gc, obj, name, method).unwrap();
+        let error = to_string(&mut gc, obj).unwrap_err();
+        assert_eq!(string(error), "toString has to return a string");
+        assert_eq!(gc.handle_count(), 0);
+    }
+
+    #[test]
+    fn test_to_number() {
+        let mut gc = CopyGC::new();
+        assert_eq!(to_number(&mut gc, TRUE), int(1));
+        assert_eq!(to_number(&mut gc, NIL), int(0));
+        for &(source, expected) in [(" 0x10 ", 16.0), ("", 0.0), ("-2.5e1", -25.0)].iter() {
+            let source = HString::from_str(&mut gc, source);
+            let number = to_number(&mut gc, source);
+            assert_eq!(HNumber::to_double(number), expected);
+        }
+        let source = HString::from_str(&mut gc, "12px");
+        assert!(HNumber::to_double(to_number(&mut gc, source)).is_nan());
+    }
+}
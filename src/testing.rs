@@ -0,0 +1,19 @@
+//! Helpers shared by the unit tests.
+
+use crate::gc::copying::CopyGC;
+use crate::heap::*;
+
+/// Small integer `value`.
+pub fn int(value: i64) -> *mut u8 {
+    HNumber::tag(value) as *mut u8
+}
+
+/// Contents of the string `value`.
+pub fn string(value: *mut u8) -> String {
+    unsafe { (*(value as *mut HString)).to_rust_string() }
+}
+
+/// Interned string `name`, for use as a property key.
+pub fn key(gc: &mut CopyGC, name: &str) -> *mut u8 {
+    gc.intern_str(name)
+}
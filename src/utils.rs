@@ -5,3 +5,27 @@ pub fn roundup(value: u32, to: u32) -> u32 {
     }
     return value + to;
 }
+
+/// Jenkins one-at-a-time hash, used for string hashes.
+pub fn compute_hash(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0;
+    for &byte in bytes {
+        hash = hash.wrapping_add(byte as u32);
+        hash = hash.wrapping_add(hash << 10);
+        hash ^= hash >> 6;
+    }
+    hash = hash.wrapping_add(hash << 3);
+    hash ^= hash >> 11;
+    hash.wrapping_add(hash << 15)
+}
+
+/// Hash of a single word, used for number keys and object identity.
+pub fn hash_word(value: u64) -> u32 {
+    let mut hash = value;
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+    hash as u32
+}
@@ -0,0 +1,580 @@
+//! Safe view on tagged values.
+//!
+//! The heap API works on raw `*mut u8` values. `Value` decodes such a value
+//! into one variant per kind, and `Ref<T>` is a handle to a heap object that
+//! is known to have the tag of `T`, so embedders can inspect and build values
+//! without casting pointers themselves.
+//!
+//! A `Ref` is backed by a `Local` of a `HandleScope`: the collector keeps its
+//! object alive and updates it when the object moves, and the `'s` lifetime
+//! keeps it from being used once the scope is gone. Calls that may allocate
+//! take the scope mutably, reads only need the `CopyGC` behind it:
+//!
+//! ```ignore
+//! let mut scope = HandleScope::new(&mut gc);
+//! let name = Value::from_str(&mut scope, "name");
+//! let object = Value::new_object(&mut scope, Value::Nil);
+//! let seven = Value::from_i64(&mut scope, 7);
+//! object.set(&mut scope, name, seven)?;
+//! scope.collect_garbage();
+//! assert_eq!(object.get(&mut scope, name).as_int(), Some(7));
+//! ```
+
+use crate::gc::copying::CopyGC;
+use crate::gc::handles::{HandleScope, Local};
+use crate::heap::*;
+use std::fmt;
+
+/// Handle to a heap object with the tag of `T`, valid while the scope it was
+/// created in is open.
+pub struct Ref<'s, T: HValTrait> {
+    local: Local<'s, T>,
+}
+
+impl<'s, T: HValTrait> Ref<'s, T> {
+    /// Returns the handle if `raw` is a `T`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` has to be an immediate, an unboxed number or point to a live
+    /// heap object.
+    pub unsafe fn from_raw(scope: &mut HandleScope<'s>, raw: *mut u8) -> Option<Ref<'s, T>> {
+        if HValue::get_tag(raw) == T::TAG {
+            Some(Ref {
+                local: scope.local(raw),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// The handle the object is kept in.
+    pub fn local(self) -> Local<'s, T> {
+        self.local
+    }
+
+    /// Current address of the object.
+    pub fn raw(self, gc: &CopyGC) -> *mut u8 {
+        self.local.get(gc)
+    }
+
+    pub fn value(self, gc: &CopyGC) -> Value<'s> {
+        Value::from_local(self.local.cast(), gc)
+    }
+
+    /// Whether both handles hold the same object.
+    pub fn same(self, gc: &CopyGC, other: Ref<T>) -> bool {
+        self.raw(gc) == other.raw(gc)
+    }
+
+    fn object(self, gc: &CopyGC) -> &T {
+        unsafe { &*(self.raw(gc) as *const T) }
+    }
+
+    /// Stores the object's new address after an operation moved it.
+    fn update(self, gc: &mut CopyGC, raw: *mut u8) {
+        self.local.set(gc, raw)
+    }
+}
+
+impl<'s, T: HValTrait> Copy for Ref<'s, T> {}
+
+impl<'s, T: HValTrait> Clone for Ref<'s, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'s, T: HValTrait> fmt::Debug for Ref<'s, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Ref({:?})", self.local)
+    }
+}
+
+impl<'s> Ref<'s, HNumber> {
+    pub fn to_f64(self, gc: &CopyGC) -> f64 {
+        HNumber::to_double(self.raw(gc))
+    }
+}
+
+impl<'s> Ref<'s, HBigInt> {
+    pub fn to_string_radix(self, gc: &CopyGC, radix: u32) -> String {
+        HBigInt::to_string_radix(self.raw(gc), radix)
+    }
+}
+
+impl<'s> Ref<'s, HString> {
+    /// Length in code points.
+    pub fn len(self, gc: &CopyGC) -> usize {
+        self.object(gc).char_length() as usize
+    }
+
+    pub fn is_empty(self, gc: &CopyGC) -> bool {
+        self.len(gc) == 0
+    }
+
+    pub fn to_rust_string(self, gc: &CopyGC) -> String {
+        self.object(gc).to_rust_string()
+    }
+}
+
+impl<'s> Ref<'s, HObject> {
+    /// Property `key`, looked up through the prototype chain.
+    pub fn get(self, scope: &mut HandleScope<'s>, key: Value<'s>) -> Value<'s> {
+        let value = HObject::get_property(self.raw(scope), key.raw(scope));
+        unsafe { Value::from_raw(scope, value) }
+    }
+
+    pub fn has(self, gc: &CopyGC, key: Value) -> bool {
+        HObject::has_property(self.raw(gc), key.raw(gc))
+    }
+
+    /// Sets an own property, or returns the exception thrown for frozen and
+    /// non-extensible objects.
+    pub fn set(
+        self,
+        scope: &mut HandleScope<'s>,
+        key: Value<'s>,
+        value: Value<'s>,
+    ) -> Result<(), Value<'s>> {
+        let (obj, key, value) = (self.raw(scope), key.raw(scope), value.raw(scope));
+        match HObject::set_property(scope, obj, key, value) {
+            Ok(obj) => {
+                self.update(scope, obj);
+                Ok(())
+            }
+            Err(thrown) => Err(unsafe { Value::from_raw(scope, thrown) }),
+        }
+    }
+
+    /// Stops new properties from being added, see
+    /// `HObject::prevent_extensions`.
+    pub fn prevent_extensions(self, gc: &mut CopyGC) {
+        HObject::prevent_extensions(self.raw(gc))
+    }
+
+    /// Stops properties from being added or deleted, see `HObject::seal`.
+    pub fn seal(self, gc: &mut CopyGC) {
+        HObject::seal(self.raw(gc))
+    }
+
+    /// Makes the object immutable, see `HObject::freeze`.
+    pub fn freeze(self, gc: &mut CopyGC) {
+        HObject::freeze(self.raw(gc))
+    }
+
+    pub fn is_extensible(self, gc: &CopyGC) -> bool {
+        HObject::is_extensible(self.raw(gc))
+    }
+
+    pub fn is_sealed(self, gc: &CopyGC) -> bool {
+        HObject::is_sealed(self.raw(gc))
+    }
+
+    pub fn is_frozen(self, gc: &CopyGC) -> bool {
+        HObject::is_frozen(self.raw(gc))
+    }
+}
+
+impl<'s> Ref<'s, HArray> {
+    pub fn len(self, gc: &CopyGC) -> usize {
+        HArray::length(self.raw(gc), false)
+    }
+
+    pub fn is_empty(self, gc: &CopyGC) -> bool {
+        self.len(gc) == 0
+    }
+
+    /// Element at `index`, `Undefined` for holes.
+    pub fn get(self, scope: &mut HandleScope<'s>, index: u32) -> Value<'s> {
+        let value = HArray::get(self.raw(scope), index);
+        unsafe { Value::from_raw(scope, value) }
+    }
+
+    /// Stores an element, or returns the exception thrown for frozen and
+    /// non-extensible arrays.
+    pub fn set(
+        self,
+        scope: &mut HandleScope<'s>,
+        index: u32,
+        value: Value<'s>,
+    ) -> Result<(), Value<'s>> {
+        let (array, value) = (self.raw(scope), value.raw(scope));
+        let result = HArray::set(scope, array, index, value);
+        self.update_with(scope, result)
+    }
+
+    pub fn push(self, scope: &mut HandleScope<'s>, value: Value<'s>) -> Result<(), Value<'s>> {
+        let (array, value) = (self.raw(scope), value.raw(scope));
+        let result = HArray::push(scope, array, value);
+        self.update_with(scope, result)
+    }
+
+    fn update_with(
+        self,
+        scope: &mut HandleScope<'s>,
+        result: Result<*mut u8, *mut u8>,
+    ) -> Result<(), Value<'s>> {
+        match result {
+            Ok(array) => {
+                self.update(scope, array);
+                Ok(())
+            }
+            Err(thrown) => Err(unsafe { Value::from_raw(scope, thrown) }),
+        }
+    }
+}
+
+impl<'s> Ref<'s, HMap> {
+    pub fn len(self, gc: &CopyGC) -> usize {
+        self.object(gc).count() as usize
+    }
+
+    pub fn is_empty(self, gc: &CopyGC) -> bool {
+        self.len(gc) == 0
+    }
+
+    pub fn get(self, scope: &mut HandleScope<'s>, key: Value<'s>) -> Option<Value<'s>> {
+        let value = self.object(scope).get(key.raw(scope))?;
+        Some(unsafe { Value::from_raw(scope, value) })
+    }
+
+    /// Inserts or replaces an entry.
+    pub fn set(self, scope: &mut HandleScope<'s>, key: Value<'s>, value: Value<'s>) {
+        let (map, key, value) = (self.raw(scope), key.raw(scope), value.raw(scope));
+        let map = HMap::set(scope, map, key, value);
+        self.update(scope, map)
+    }
+}
+
+impl<'s> Ref<'s, HFunction> {
+    /// Number of declared arguments.
+    pub fn argc(self, gc: &CopyGC) -> u32 {
+        self.object(gc).argc()
+    }
+
+    /// Calls the function, see `HFunction::call`. Returns the result or the
+    /// thrown value.
+    ///
+    /// # Safety
+    ///
+    /// Script functions jump to their compiled code, which has to be a valid
+    /// `CompiledCode` for this function.
+    pub unsafe fn call(
+        self,
+        scope: &mut HandleScope<'s>,
+        receiver: Value<'s>,
+        args: &[Value<'s>],
+    ) -> Result<Value<'s>, Value<'s>> {
+        let (function, receiver) = (self.raw(scope), receiver.raw(scope));
+        let args: Vec<*mut u8> = args.iter().map(|arg| arg.raw(scope)).collect();
+        match HFunction::call(scope, function, receiver, &args) {
+            Ok(result) => Ok(Value::from_raw(scope, result)),
+            Err(thrown) => Err(Value::from_raw(scope, thrown)),
+        }
+    }
+}
+
+impl<'s> Ref<'s, HContext> {
+    pub fn slots(self, gc: &CopyGC) -> u32 {
+        self.object(gc).slots()
+    }
+}
+
+/// An integer in `MIN_SMALL_INT..=MAX_SMALL_INT`, which is stored unboxed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SmallInt(i64);
+
+impl SmallInt {
+    /// Returns `None` for integers out of the small integer range, see
+    /// `Value::from_i64` for those.
+    pub fn new(value: i64) -> Option<SmallInt> {
+        if (MIN_SMALL_INT..=MAX_SMALL_INT).contains(&value) {
+            Some(SmallInt(value))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(self) -> i64 {
+        self.0
+    }
+}
+
+/// A decoded tagged value. Heap values are held by `Ref`s, so a `Value`
+/// lives as long as the scope it was decoded in.
+#[derive(Copy, Clone, Debug)]
+pub enum Value<'s> {
+    Nil,
+    /// Undefined, array holes decode to it as well.
+    Undefined,
+    Bool(bool),
+    SmallInt(SmallInt),
+    /// Heap allocated or NaN-boxed double.
+    Number(Ref<'s, HNumber>),
+    BigInt(Ref<'s, HBigInt>),
+    String(Ref<'s, HString>),
+    Object(Ref<'s, HObject>),
+    Array(Ref<'s, HArray>),
+    Function(Ref<'s, HFunction>),
+    Context(Ref<'s, HContext>),
+    Map(Ref<'s, HMap>),
+    ExternData(Ref<'s, HExternData>),
+    /// Objects the VM uses internally, like shapes and element stores.
+    Internal(Ref<'s, HValue>),
+}
+
+impl<'s> Value<'s> {
+    /// Decodes a tagged value, heap values get a handle in `scope`.
+    ///
+    /// # Safety
+    ///
+    /// `raw` has to be an immediate, an unboxed number or point to a live
+    /// heap object.
+    pub unsafe fn from_raw(scope: &mut HandleScope<'s>, raw: *mut u8) -> Value<'s> {
+        Self::decode(raw, || scope.local(raw))
+    }
+
+    /// Decodes the value held by `local`, sharing the handle.
+    pub fn from_local(local: Local<'s, HValue>, gc: &CopyGC) -> Value<'s> {
+        Self::decode(local.get(gc), || local)
+    }
+
+    /// Immediates are decoded from `raw`, heap values are kept in the handle
+    /// `local` returns.
+    fn decode<F: FnOnce() -> Local<'s, HValue>>(raw: *mut u8, local: F) -> Value<'s> {
+        if HNumber::is_int(raw) {
+            return Value::SmallInt(SmallInt(HNumber::untag(raw)));
+        }
+        fn wrap<'s, T: HValTrait>(local: Local<'s, HValue>) -> Ref<'s, T> {
+            Ref {
+                local: local.cast(),
+            }
+        }
+        match HValue::get_tag(raw) {
+            HeapTag::Nil => Value::Nil,
+            HeapTag::Undefined => Value::Undefined,
+            HeapTag::Boolean => Value::Bool(HBoolean::value(raw)),
+            HeapTag::Number => Value::Number(wrap(local())),
+            HeapTag::BigInt => Value::BigInt(wrap(local())),
+            HeapTag::String => Value::String(wrap(local())),
+            HeapTag::Object => Value::Object(wrap(local())),
+            HeapTag::Array => Value::Array(wrap(local())),
+            HeapTag::Function => Value::Function(wrap(local())),
+            HeapTag::Context => Value::Context(wrap(local())),
+            HeapTag::Map => Value::Map(wrap(local())),
+            HeapTag::ExternData => Value::ExternData(wrap(local())),
+            HeapTag::Shape | HeapTag::Elements => Value::Internal(wrap(local())),
+        }
+    }
+
+    /// The current tagged value.
+    pub fn raw(self, gc: &CopyGC) -> *mut u8 {
+        match self {
+            Value::Nil => NIL,
+            Value::Undefined => UNDEFINED,
+            Value::Bool(value) => HBoolean::new(value),
+            Value::SmallInt(value) => HNumber::tag(value.get()) as *mut u8,
+            Value::Number(r) => r.raw(gc),
+            Value::BigInt(r) => r.raw(gc),
+            Value::String(r) => r.raw(gc),
+            Value::Object(r) => r.raw(gc),
+            Value::Array(r) => r.raw(gc),
+            Value::Function(r) => r.raw(gc),
+            Value::Context(r) => r.raw(gc),
+            Value::Map(r) => r.raw(gc),
+            Value::ExternData(r) => r.raw(gc),
+            Value::Internal(r) => r.raw(gc),
+        }
+    }
+
+    /// Integer value, a double when it does not fit a small integer.
+    pub fn from_i64(scope: &mut HandleScope<'s>, value: i64) -> Value<'s> {
+        if let Some(value) = SmallInt::new(value) {
+            return Value::SmallInt(value);
+        }
+        let number = HNumber::from_i64(scope, value);
+        unsafe { Value::from_raw(scope, number) }
+    }
+
+    pub fn from_f64(scope: &mut HandleScope<'s>, value: f64) -> Value<'s> {
+        let number = HNumber::new(scope, value);
+        unsafe { Value::from_raw(scope, number) }
+    }
+
+    pub fn from_str(scope: &mut HandleScope<'s>, value: &str) -> Value<'s> {
+        let string = HString::from_str(scope, value);
+        Value::String(Ref {
+            local: scope.local(string),
+        })
+    }
+
+    /// Empty object with the given prototype.
+    pub fn new_object(scope: &mut HandleScope<'s>, proto: Value<'s>) -> Ref<'s, HObject> {
+        let proto = proto.raw(scope);
+        let object = HObject::new(scope, proto);
+        Ref {
+            local: scope.local(object),
+        }
+    }
+
+    pub fn new_array(scope: &mut HandleScope<'s>, capacity: u32) -> Ref<'s, HArray> {
+        let array = HArray::new(scope, capacity);
+        Ref {
+            local: scope.local(array),
+        }
+    }
+
+    pub fn new_map(scope: &mut HandleScope<'s>, capacity: u32) -> Ref<'s, HMap> {
+        let map = HMap::new(scope, capacity);
+        Ref {
+            local: scope.local(map),
+        }
+    }
+
+    pub fn tag(self, gc: &CopyGC) -> HeapTag {
+        HValue::get_tag(self.raw(gc))
+    }
+
+    /// Whether both values are the same immediate or the same object.
+    pub fn same(self, gc: &CopyGC, other: Value) -> bool {
+        self.raw(gc) == other.raw(gc)
+    }
+
+    pub fn is_nil(self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn as_bool(self) -> Option<bool> {
+        match self {
+            Value::Bool(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(self) -> Option<i64> {
+        match self {
+            Value::SmallInt(value) => Some(value.get()),
+            _ => None,
+        }
+    }
+
+    /// Numeric value of small integers, doubles and BigInts.
+    pub fn as_f64(self, gc: &CopyGC) -> Option<f64> {
+        match self {
+            Value::SmallInt(value) => Some(value.get() as f64),
+            Value::Number(r) => Some(r.to_f64(gc)),
+            Value::BigInt(r) => Some(HBigInt::to_double(r.raw(gc))),
+            _ => None,
+        }
+    }
+
+    pub fn as_bigint(self) -> Option<Ref<'s, HBigInt>> {
+        match self {
+            Value::BigInt(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_string(self) -> Option<Ref<'s, HString>> {
+        match self {
+            Value::String(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(self) -> Option<Ref<'s, HObject>> {
+        match self {
+            Value::Object(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(self) -> Option<Ref<'s, HArray>> {
+        match self {
+            Value::Array(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(self) -> Option<Ref<'s, HFunction>> {
+        match self {
+            Value::Function(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_context(self) -> Option<Ref<'s, HContext>> {
+        match self {
+            Value::Context(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(self) -> Option<Ref<'s, HMap>> {
+        match self {
+            Value::Map(r) => Some(r),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_roundtrip() {
+        let mut gc = CopyGC::new();
+        let mut scope = HandleScope::new(&mut gc);
+        let name = Value::from_str(&mut scope, "name");
+        let object = Value::new_object(&mut scope, Value::Nil);
+        let seven = Value::from_i64(&mut scope, 7);
+        object.set(&mut scope, name, seven).unwrap();
+        scope.collect_garbage();
+        assert_eq!(object.get(&mut scope, name).as_int(), Some(7));
+        assert!(!object.has(&scope, Value::Bool(true)));
+
+        let half = Value::from_f64(&mut scope, 0.5);
+        assert_eq!(half.as_f64(&scope), Some(0.5));
+        assert_eq!(Value::from_f64(&mut scope, 2.0).as_int(), Some(2));
+        let big = Value::from_i64(&mut scope, MAX_SMALL_INT + 1);
+        assert_eq!(big.as_int(), None);
+        assert_eq!(big.as_f64(&scope), Some((MAX_SMALL_INT + 1) as f64));
+        assert!(SmallInt::new(MAX_SMALL_INT + 1).is_none());
+
+        let array = Value::new_array(&mut scope, 0);
+        let value = object.value(&scope);
+        array.push(&mut scope, value).unwrap();
+        array.push(&mut scope, half).unwrap();
+        array.set(&mut scope, 3, Value::Bool(false)).unwrap();
+        scope.collect_garbage();
+        assert_eq!(array.len(&scope), 4);
+        assert!(matches!(array.get(&mut scope, 2), Value::Undefined));
+        assert_eq!(array.get(&mut scope, 3).as_bool(), Some(false));
+
+        let stored = array.get(&mut scope, 0).as_object().unwrap();
+        assert!(stored.same(&scope, object));
+        assert_eq!(stored.get(&mut scope, name).as_int(), Some(7));
+        assert!(array.get(&mut scope, 1).as_string().is_none());
+        let name_string = name.as_string().unwrap();
+        assert_eq!(name_string.to_rust_string(&scope), "name");
+
+        for &value in &[Value::Nil, Value::Undefined, Value::Bool(true)] {
+            let raw = value.raw(&scope);
+            assert!(unsafe { Value::from_raw(&mut scope, raw) }.same(&scope, value));
+        }
+        assert_eq!(object.value(&scope).tag(&scope), HeapTag::Object);
+        let raw = object.raw(&scope);
+        assert!(unsafe { Ref::<HArray>::from_raw(&mut scope, raw) }.is_none());
+
+        let other = Value::from_str(&mut scope, "other");
+        object.prevent_extensions(&mut scope);
+        assert!(!object.is_extensible(&scope) && !object.is_sealed(&scope));
+        assert!(object.set(&mut scope, other, Value::Nil).is_err());
+        object.seal(&mut scope);
+        let eight = Value::from_i64(&mut scope, 8);
+        object.set(&mut scope, name, eight).unwrap();
+        assert!(object.is_sealed(&scope) && !object.is_frozen(&scope));
+    }
+}